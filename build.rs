@@ -0,0 +1,16 @@
+use std::path::Path;
+
+/// Built by wasm-pack and imported by `ui/app.js`, see the UI section of the README
+const UI_PKG_FILES: [&str; 2] = ["ui/pkg/tiauth2_wasm.js", "ui/pkg/tiauth2_wasm_bg.wasm"];
+
+/// `ui/` is embedded with `include_dir!`, which would silently leave out the WASM package if it was not
+/// built, so building fails instead
+fn main() {
+    println!("cargo:rerun-if-changed=ui/pkg");
+    for file in UI_PKG_FILES {
+        println!("cargo:rerun-if-changed={}", file);
+        if !Path::new(file).exists() {
+            panic!("{} is missing, build it first with: wasm-pack build tiauth2-wasm --target web --out-dir ../ui/pkg", file);
+        }
+    }
+}
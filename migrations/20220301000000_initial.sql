@@ -0,0 +1,68 @@
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    usp_hex TEXT NOT NULL,
+    password_file TEXT NOT NULL,
+    email TEXT,
+    email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+    admin BOOLEAN NOT NULL DEFAULT FALSE,
+    disabled BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE UNIQUE INDEX users_usp_hex_idx ON users (usp_hex);
+CREATE UNIQUE INDEX users_email_idx ON users (email);
+
+-- Ids are fixed: 0 OPAQUE, 1 token signing, 2 symmetric
+CREATE TABLE keys (
+    id INTEGER PRIMARY KEY,
+    algorithm TEXT NOT NULL,
+    public TEXT NOT NULL,
+    private TEXT NOT NULL,
+    public_format TEXT NOT NULL,
+    public_encoding TEXT NOT NULL,
+    private_format TEXT NOT NULL,
+    private_encoding TEXT NOT NULL
+);
+
+CREATE TABLE refreshtokens (
+    id SERIAL PRIMARY KEY,
+    family_id TEXT NOT NULL,
+    user_usph TEXT NOT NULL,
+    access_value TEXT NOT NULL,
+    id_token_value TEXT NOT NULL,
+    iat INTEGER NOT NULL,
+    exp INTEGER NOT NULL,
+    nonce TEXT NOT NULL
+);
+CREATE INDEX refreshtokens_family_id_idx ON refreshtokens (family_id);
+CREATE INDEX refreshtokens_user_usph_idx ON refreshtokens (user_usph);
+
+CREATE TABLE clients (
+    id SERIAL PRIMARY KEY,
+    client_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    redirect_uris TEXT NOT NULL
+);
+CREATE UNIQUE INDEX clients_client_id_idx ON clients (client_id);
+
+CREATE TABLE totp (
+    id SERIAL PRIMARY KEY,
+    user_usph TEXT NOT NULL,
+    secret TEXT NOT NULL
+);
+CREATE UNIQUE INDEX totp_user_usph_idx ON totp (user_usph);
+
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    user_usph TEXT NOT NULL,
+    code_hash TEXT NOT NULL
+);
+CREATE UNIQUE INDEX recovery_codes_code_hash_idx ON recovery_codes (code_hash);
+CREATE INDEX recovery_codes_user_usph_idx ON recovery_codes (user_usph);
+
+CREATE TABLE webauthn_credentials (
+    id SERIAL PRIMARY KEY,
+    user_usph TEXT NOT NULL,
+    credential_id TEXT NOT NULL,
+    passkey TEXT NOT NULL
+);
+CREATE UNIQUE INDEX webauthn_credentials_credential_id_idx ON webauthn_credentials (credential_id);
+CREATE INDEX webauthn_credentials_user_usph_idx ON webauthn_credentials (user_usph);
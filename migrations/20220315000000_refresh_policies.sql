@@ -0,0 +1,14 @@
+ALTER TABLE clients
+    ADD COLUMN refresh_issue BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN refresh_scope TEXT,
+    ADD COLUMN refresh_exp INTEGER NOT NULL DEFAULT 3600,
+    ADD COLUMN refresh_sliding BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN refresh_max_age INTEGER NOT NULL DEFAULT 2592000,
+    ADD COLUMN refresh_idle_timeout INTEGER;
+
+-- Existing families were not bound to a client, they keep the default policy
+ALTER TABLE refreshtokens
+    ADD COLUMN client_id TEXT NOT NULL DEFAULT '',
+    ADD COLUMN family_iat INTEGER;
+UPDATE refreshtokens SET family_iat = iat;
+ALTER TABLE refreshtokens ALTER COLUMN family_iat SET NOT NULL;
@@ -0,0 +1,19 @@
+-- Rotated tokens are kept until they expire, pointing to the token that replaced them
+ALTER TABLE refreshtokens
+    ADD COLUMN successor_id INTEGER,
+    ADD COLUMN rotated_at INTEGER;
+
+ALTER TABLE clients
+    ADD COLUMN refresh_reuse_window INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE refresh_reuse_events (
+    id SERIAL PRIMARY KEY,
+    family_id TEXT NOT NULL,
+    user_usph TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    refresh_id INTEGER NOT NULL,
+    occurred_at INTEGER NOT NULL,
+    -- False if the reuse fell within the client's reuse window and the successor was returned
+    family_revoked BOOLEAN NOT NULL
+);
+CREATE INDEX refresh_reuse_events_user_usph_idx ON refresh_reuse_events (user_usph);
@@ -0,0 +1,22 @@
+CREATE TABLE audit_events (
+    id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    user_usph TEXT,
+    client_id TEXT,
+    ip TEXT,
+    -- JSON object with event specific fields
+    detail TEXT NOT NULL,
+    occurred_at INTEGER NOT NULL
+);
+CREATE INDEX audit_events_user_usph_idx ON audit_events (user_usph);
+CREATE INDEX audit_events_kind_idx ON audit_events (kind);
+
+-- Events can be deleted when they are no longer retained, but never changed
+CREATE FUNCTION audit_events_no_update() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_update BEFORE UPDATE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_no_update();
@@ -0,0 +1,3 @@
+-- Space-separated origins allowed to call the token endpoint from a browser. If NULL, the origins of the
+-- redirect URIs are allowed.
+ALTER TABLE clients ADD COLUMN allowed_origins TEXT;
@@ -0,0 +1,9 @@
+CREATE TABLE consents (
+    id SERIAL PRIMARY KEY,
+    user_usph TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    -- Space-separated scopes the user agreed to, openid is implied
+    scope TEXT NOT NULL,
+    granted_at INTEGER NOT NULL
+);
+CREATE UNIQUE INDEX consents_user_client_idx ON consents (user_usph, client_id);
@@ -0,0 +1,2 @@
+-- Display name, returned as the profile's name and set by the user through the account API
+ALTER TABLE users ADD COLUMN name TEXT;
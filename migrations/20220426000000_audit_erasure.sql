@@ -0,0 +1,12 @@
+-- Erasing a user may replace their usp_hex with a pseudonym starting with 'erased:' and remove the client
+-- address, any other change is still rejected
+CREATE OR REPLACE FUNCTION audit_events_no_update() RETURNS trigger AS $$
+BEGIN
+    IF NEW.id = OLD.id AND NEW.kind = OLD.kind AND NEW.client_id IS NOT DISTINCT FROM OLD.client_id
+        AND NEW.detail = OLD.detail AND NEW.occurred_at = OLD.occurred_at
+        AND NEW.ip IS NULL AND NEW.user_usph LIKE 'erased:%' THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,2 @@
+-- Only first-party clients can be granted the admin scope
+ALTER TABLE clients ADD COLUMN first_party BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- Only verified addresses have to be unique, so claiming an address without verifying it does not keep
+-- its owner from using it
+DROP INDEX users_email_idx;
+CREATE UNIQUE INDEX users_email_idx ON users (email) WHERE email_verified;
@@ -0,0 +1,36 @@
+use serde::Serialize;
+use crate::auth::tokens::{ACCOUNT_SCOPE, ADMIN_SCOPE};
+use crate::config::ISS;
+
+/// OpenID Connect provider metadata, served at `/.well-known/openid-configuration`
+#[derive(Serialize, Debug)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|v| v.to_string()).collect()
+}
+
+pub fn discovery_document() -> DiscoveryDocument {
+    DiscoveryDocument {
+        issuer: ISS.to_string(),
+        authorization_endpoint: format!("{}/oauth/authorize/", ISS),
+        token_endpoint: format!("{}/oauth/token/", ISS),
+        response_types_supported: strings(&["code"]),
+        grant_types_supported: strings(&["authorization_code", "refresh_token"]),
+        subject_types_supported: strings(&["public"]),
+        // Ed448 signatures
+        id_token_signing_alg_values_supported: strings(&["EdDSA"]),
+        code_challenge_methods_supported: strings(&["S256"]),
+        scopes_supported: strings(&["openid", ADMIN_SCOPE, ACCOUNT_SCOPE]),
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use crate::auth::auth::{symmetric_crypt, symmetric_decrypt};
+use crate::auth::tokens::get_symmetric_key_bytes;
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::error::Error;
+use crate::utility::{dec_b64url, enc_b64url, rng_urlsafe, utc_timestamp};
+
+const VERIFY_EXP: u64 = 24 * 60 * 60;
+const RESET_EXP: u64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailPurpose {
+    Verify,
+    Reset
+}
+
+/// Sent by mail as a link, encrypted (and thereby authenticated) with the symmetric key. The id is
+/// kept in the KV store until the token is used, so each link only works once.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailToken {
+    pub id: String,
+    pub purpose: EmailPurpose,
+    pub user_usph: String,
+    pub email: String,
+    pub exp: u64
+}
+
+fn email_token_key(id: &str) -> String {
+    format!("email_token:{}", id)
+}
+
+fn encrypt_email_token(symmetric_key: &[u8], email_token: &EmailToken) -> Result<String, Error> {
+    let token_bytes = serde_json::to_vec(email_token)?;
+    let token = symmetric_crypt(symmetric_key, token_bytes)?;
+    Ok(enc_b64url(&token))
+}
+
+fn decrypt_email_token(symmetric_key: &[u8], email_token: &str) -> Result<EmailToken, Error> {
+    let token_bytes = dec_b64url(email_token).map_err(|_| Error::InvalidEmailToken)?;
+    let token = symmetric_decrypt(symmetric_key, token_bytes).map_err(|_| Error::InvalidEmailToken)?;
+    serde_json::from_slice(&token).map_err(|_| Error::InvalidEmailToken)
+}
+
+pub async fn new_email_token(dsrc: &Source, purpose: EmailPurpose, user_usph: &str, email: &str) -> Result<String, Error> {
+    let symmetric_key = get_symmetric_key_bytes(dsrc).await?;
+    let expire = match purpose {
+        EmailPurpose::Verify => VERIFY_EXP,
+        EmailPurpose::Reset => RESET_EXP
+    };
+
+    let email_token = EmailToken {
+        id: rng_urlsafe(16),
+        purpose,
+        user_usph: user_usph.to_owned(),
+        email: email.to_owned(),
+        exp: utc_timestamp() + expire
+    };
+    dsrc.kv.store_json(&email_token_key(&email_token.id), &purpose, expire as usize).await?;
+
+    encrypt_email_token(&symmetric_key, &email_token)
+}
+
+/// Checks the token without using it up, see `use_email_token`
+pub async fn check_email_token(dsrc: &Source, email_token: &str, purpose: EmailPurpose) -> Result<EmailToken, Error> {
+    let symmetric_key = get_symmetric_key_bytes(dsrc).await?;
+    let email_token = decrypt_email_token(&symmetric_key, email_token)?;
+
+    if email_token.purpose != purpose || email_token.exp < utc_timestamp() {
+        return Err(Error::InvalidEmailToken)
+    }
+    let stored: Option<EmailPurpose> = dsrc.kv.get_json(&email_token_key(&email_token.id)).await?;
+    if stored != Some(purpose) {
+        return Err(Error::InvalidEmailToken)
+    }
+
+    Ok(email_token)
+}
+
+/// Fails if the token was already used
+pub async fn use_email_token(dsrc: &Source, email_token_id: &str) -> Result<(), Error> {
+    if !dsrc.kv.delete(&email_token_key(email_token_id)).await? {
+        return Err(Error::InvalidEmailToken)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::keyutil::new_symmetric_keypair;
+    use super::*;
+
+    #[test]
+    fn test_email_token() {
+        let key = new_symmetric_keypair();
+        let key_bytes = dec_b64url(&key.private).unwrap();
+        let email_token = EmailToken {
+            id: "abc".to_string(),
+            purpose: EmailPurpose::Reset,
+            user_usph: "user".to_string(),
+            email: "user@example.com".to_string(),
+            exp: 0
+        };
+        let token = encrypt_email_token(&key_bytes, &email_token).unwrap();
+        let decrypted = decrypt_email_token(&key_bytes, &token).unwrap();
+        assert_eq!(decrypted.purpose, EmailPurpose::Reset);
+        assert_eq!(decrypted.email, email_token.email);
+
+        let other_key = new_symmetric_keypair();
+        let other_bytes = dec_b64url(&other_key.private).unwrap();
+        assert!(matches!(decrypt_email_token(&other_bytes, &token), Err(Error::InvalidEmailToken)));
+    }
+}
@@ -1,3 +1,9 @@
-mod auth;
+pub(crate) mod auth;
+pub mod discovery;
+pub mod email;
 pub mod keyutil;
-pub mod tokens;
\ No newline at end of file
+pub mod password;
+pub mod throttle;
+pub mod totp;
+pub mod tokens;
+pub mod webauthn;
@@ -0,0 +1,17 @@
+use opaquebind::client::{register_client, register_client_finish};
+use opaquebind::server::{register_server, register_server_finish};
+use crate::data::key::get_opaque_public;
+use crate::data::source::Source;
+use crate::error::Error;
+
+/// Runs both sides of an OPAQUE registration, for when the server sets a password itself. The password
+/// is known to the caller, so this must never be used for passwords sent by users.
+pub async fn new_password_file(dsrc: &Source, password: &str) -> Result<String, Error> {
+    let public_key = get_opaque_public(dsrc).await?;
+
+    let (client_request, client_state) = register_client(password.to_owned())?;
+    let (server_response, server_state) = register_server(client_request, public_key)?;
+    let client_finish = register_client_finish(client_state, server_response)?;
+
+    Ok(register_server_finish(client_finish, server_state)?)
+}
@@ -0,0 +1,138 @@
+use std::net::IpAddr;
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::error::Error;
+
+struct Policy {
+    /// Failures allowed before backing off
+    free_attempts: i64,
+    max_backoff: u64,
+    lockout_threshold: i64,
+    lockout: u64,
+    /// Failures are forgotten after this many seconds without a new attempt
+    window: usize
+}
+
+const USER_POLICY: Policy = Policy {
+    free_attempts: 3,
+    max_backoff: 5 * 60,
+    lockout_threshold: 10,
+    lockout: 15 * 60,
+    window: 60 * 60
+};
+
+/// More lenient, as many users can share an address
+const IP_POLICY: Policy = Policy {
+    free_attempts: 10,
+    max_backoff: 60,
+    lockout_threshold: 100,
+    lockout: 15 * 60,
+    window: 60 * 60
+};
+
+/// Seconds that have to pass after the last failure before another attempt is allowed
+fn required_wait(policy: &Policy, failures: i64) -> u64 {
+    if failures >= policy.lockout_threshold {
+        policy.lockout
+    } else if failures < policy.free_attempts {
+        0
+    } else {
+        let exponent = (failures - policy.free_attempts).min(32) as u32;
+        2u64.pow(exponent).min(policy.max_backoff)
+    }
+}
+
+/// Waits indexed by the number of failures, up to the lockout
+fn wait_table(policy: &Policy) -> Vec<u64> {
+    (0..=policy.lockout_threshold).map(|failures| required_wait(policy, failures)).collect()
+}
+
+fn throttle_key(scope: &str) -> String {
+    format!("login_throttle:{}", scope)
+}
+
+fn user_scope(user_usph: &str) -> String {
+    format!("user:{}", user_usph)
+}
+
+fn ip_scope(ip: &IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// The username's throttle key, addresses are not tied to a user
+pub fn user_kv_keys(user_usph: &str) -> Vec<String> {
+    vec![throttle_key(&user_scope(user_usph))]
+}
+
+async fn retry_after(dsrc: &Source, policy: &Policy, scope: &str, count: bool) -> Result<u64, Error> {
+    dsrc.kv.take_attempt(&throttle_key(scope), &wait_table(policy), policy.window, count).await
+}
+
+/// Returns `Error::LoginThrottled` if either the username or the address has to back off, without
+/// counting an attempt. Usernames are tracked whether an account exists or not, so this does not
+/// reveal which ones do.
+pub async fn check_login_allowed(dsrc: &Source, user_usph: &str, ip: &IpAddr) -> Result<(), Error> {
+    let user_retry = retry_after(dsrc, &USER_POLICY, &user_scope(user_usph), false).await?;
+    let ip_retry = retry_after(dsrc, &IP_POLICY, &ip_scope(ip), false).await?;
+
+    let retry = user_retry.max(ip_retry);
+    if retry > 0 {
+        return Err(Error::LoginThrottled(retry))
+    }
+    Ok(())
+}
+
+/// Like `check_login_allowed`, but counts the attempt as a failure before the credentials are
+/// verified, in the same step as the check. Concurrent guesses therefore cannot all pass before the
+/// first failure is recorded. A successful attempt is uncounted again by `reset_login_failures`.
+pub async fn start_login_attempt(dsrc: &Source, user_usph: &str, ip: &IpAddr) -> Result<(), Error> {
+    let user_scope = user_scope(user_usph);
+    let user_retry = retry_after(dsrc, &USER_POLICY, &user_scope, true).await?;
+    if user_retry > 0 {
+        return Err(Error::LoginThrottled(user_retry))
+    }
+    let ip_retry = retry_after(dsrc, &IP_POLICY, &ip_scope(ip), true).await?;
+    if ip_retry > 0 {
+        dsrc.kv.refund_attempt(&throttle_key(&user_scope)).await?;
+        return Err(Error::LoginThrottled(ip_retry))
+    }
+    Ok(())
+}
+
+/// Only the username is reset on success, so an address cannot reset its own count. The address
+/// only gets back the attempt counted by `start_login_attempt`.
+pub async fn reset_login_failures(dsrc: &Source, user_usph: &str, ip: &IpAddr) -> Result<(), Error> {
+    dsrc.kv.delete(&throttle_key(&user_scope(user_usph))).await?;
+    dsrc.kv.refund_attempt(&throttle_key(&ip_scope(ip))).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_wait() {
+        assert_eq!(required_wait(&USER_POLICY, 0), 0);
+        assert_eq!(required_wait(&USER_POLICY, 2), 0);
+        assert_eq!(required_wait(&USER_POLICY, 3), 1);
+        assert_eq!(required_wait(&USER_POLICY, 5), 4);
+        assert_eq!(required_wait(&USER_POLICY, 9), USER_POLICY.max_backoff);
+        assert_eq!(required_wait(&USER_POLICY, 10), USER_POLICY.lockout);
+        assert_eq!(required_wait(&IP_POLICY, 1000), IP_POLICY.lockout);
+    }
+
+    #[test]
+    fn test_wait_table() {
+        // The attempt script uses the last entry for any number of failures beyond the table
+        for policy in [&USER_POLICY, &IP_POLICY] {
+            let waits = wait_table(policy);
+            assert_eq!(waits.len() as i64, policy.lockout_threshold + 1);
+            assert_eq!(waits[0], 0);
+            assert_eq!(*waits.last().unwrap(), policy.lockout);
+            for (failures, wait) in waits.iter().enumerate() {
+                assert_eq!(*wait, required_wait(policy, failures as i64));
+            }
+        }
+    }
+}
@@ -1,20 +1,39 @@
 use jsonwebtoken::Algorithm::ED448;
-use jsonwebtoken::{encode, Header, EncodingKey};
+use jsonwebtoken::{decode, encode, DecodingKey, Header, EncodingKey, Validation};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
+use crate::data::client::{Client, get_client};
 use crate::data::key;
 use crate::data::source::Source;
 use crate::auth::auth::{symmetric_crypt, symmetric_decrypt};
 use crate::config::{AUD, ISS};
-use crate::data::refresh::{delete_family, get_refresh_by_id, refresh_save, refresh_transaction, SavedRefreshToken};
+use crate::data::db::Tx;
+use crate::data::refresh::{delete_expired_batch, delete_family_tx, get_refresh_by_id_tx, lock_refresh_by_id, refresh_save, RefreshReuseEvent, rotate_refresh, save_reuse_event, SavedRefreshToken};
 use crate::error::Error;
 use crate::utility::{dec_b64url, enc_b64url, enc_struct, rng_urlsafe, utc_timestamp};
 
 const ID_EXP: u64 = 10 * 60 * 60;
 pub const ACCESS_EXP: u64 = 1 * 60 * 60;
 const REFRESH_EXP: i32 = 1 * 60 * 60;
+const REFRESH_MAX_AGE: i32 = 30 * 24 * 60 * 60;
 
 const GRACE_PERIOD: i32 = 3 * 60;
 
+/// Grants access to the admin API, only granted to users marked as admin
+pub const ADMIN_SCOPE: &str = "admin";
+/// Grants access to the account API and second factor enrollment, only granted to first-party clients
+pub const ACCOUNT_SCOPE: &str = "account";
+
+/// Authentication methods references (RFC 8176) used in the `amr` claim
+pub const AMR_PASSWORD: &str = "pwd";
+pub const AMR_OTP: &str = "otp";
+pub const AMR_HWK: &str = "hwk";
+pub const AMR_MFA: &str = "mfa";
+
+const ACR_SINGLE_FACTOR: &str = "1";
+const ACR_MULTI_FACTOR: &str = "2";
+
 #[derive(Serialize, Deserialize)]
 struct RefreshToken {
     pub id: i32,
@@ -27,7 +46,12 @@ struct AccessTokenUntimed {
     pub sub: String,
     pub iss: String,
     pub aud: Vec<String>,
-    pub scope: String
+    pub scope: String,
+    /// Missing in tokens saved before it was added, which are then never considered recent
+    #[serde(default)]
+    pub auth_time: u64,
+    #[serde(default)]
+    pub amr: Vec<String>
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,19 +60,44 @@ struct IdTokenUntimed {
     pub iss: String,
     pub aud: Vec<String>,
     pub auth_time: u64,
-    pub nonce: String
+    pub nonce: String,
+    #[serde(default)]
+    pub amr: Vec<String>,
+    #[serde(default)]
+    pub acr: String
 }
 
 #[derive(Serialize, Deserialize)]
-struct AccessToken {
+pub struct AccessToken {
     pub sub: String,
     pub iss: String,
     pub aud: Vec<String>,
     pub scope: String,
+    /// Time of the login, which stays the same when the token is refreshed
+    #[serde(default)]
+    pub auth_time: u64,
+    #[serde(default)]
+    pub amr: Vec<String>,
     pub iat: u64,
     pub exp: u64
 }
 
+impl AccessToken {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope)
+    }
+
+    /// Whether the user logged in at most `max_age` seconds ago
+    pub fn recent_login(&self, max_age: u64, utc_now: u64) -> bool {
+        utc_now <= self.auth_time + max_age
+    }
+
+    /// Whether the login used a second factor
+    pub fn multi_factor(&self) -> bool {
+        self.amr.iter().any(|m| m == AMR_MFA)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct IdToken {
     pub sub: String,
@@ -56,6 +105,8 @@ struct IdToken {
     pub aud: Vec<String>,
     pub auth_time: u64,
     pub nonce: String,
+    pub amr: Vec<String>,
+    pub acr: String,
     pub iat: u64,
     pub exp: u64
 }
@@ -63,16 +114,103 @@ struct IdToken {
 pub struct Tokens {
     pub access_token: String,
     pub id_token: String,
-    pub refresh_token: String,
+    /// None if the client's policy does not issue refresh tokens for the scope
+    pub refresh_token: Option<String>,
     pub returned_scope: String
 }
 
+/// How refresh tokens are issued to a client and when they expire
+pub struct RefreshPolicy {
+    pub issue: bool,
+    pub required_scope: Option<String>,
+    pub exp: i32,
+    pub sliding: bool,
+    pub max_age: i32,
+    pub idle_timeout: Option<i32>,
+    /// Seconds after rotation in which the old token returns the same successor
+    pub reuse_window: i32
+}
+
+impl Default for RefreshPolicy {
+    /// Applies to families that are not bound to a client
+    fn default() -> Self {
+        Self {
+            issue: true,
+            required_scope: None,
+            exp: REFRESH_EXP,
+            sliding: false,
+            max_age: REFRESH_MAX_AGE,
+            idle_timeout: None,
+            reuse_window: 0
+        }
+    }
+}
+
+impl From<&Client> for RefreshPolicy {
+    fn from(client: &Client) -> Self {
+        Self {
+            issue: client.refresh_issue,
+            required_scope: client.refresh_scope.clone(),
+            exp: client.refresh_exp,
+            sliding: client.refresh_sliding,
+            max_age: client.refresh_max_age,
+            idle_timeout: client.refresh_idle_timeout,
+            reuse_window: client.refresh_reuse_window
+        }
+    }
+}
+
+impl RefreshPolicy {
+    fn issues_for(&self, scope: &str) -> bool {
+        self.issue && self.required_scope.as_deref()
+            .map_or(true, |required| scope.split_whitespace().any(|s| s == required))
+    }
+
+    fn first_exp(&self, utc_now: i32) -> i32 {
+        utc_now + self.exp.min(self.max_age)
+    }
+
+    /// Expiry of the token replacing `saved_refresh`, never beyond the maximum family age
+    fn next_exp(&self, saved_refresh: &SavedRefreshToken, utc_now: i32) -> i32 {
+        if self.sliding {
+            (utc_now + self.exp).min(saved_refresh.family_iat + self.max_age)
+        } else {
+            saved_refresh.exp
+        }
+    }
+
+    /// Checks the family age and idle time, the expiry of the token itself is checked separately
+    fn allows(&self, saved_refresh: &SavedRefreshToken, utc_now: i32) -> bool {
+        if !self.issue || utc_now > saved_refresh.family_iat + self.max_age {
+            return false
+        }
+        match self.idle_timeout {
+            Some(idle_timeout) => utc_now <= saved_refresh.iat + idle_timeout,
+            None => true
+        }
+    }
+
+    /// Whether a token rotated at `rotated_at` may still return its successor, never without a reuse window
+    fn returns_successor(&self, rotated_at: i32, utc_now: i32) -> bool {
+        self.reuse_window > 0 && utc_now < rotated_at + self.reuse_window
+    }
+}
+
+/// A family whose client was removed can no longer be refreshed
+async fn family_refresh_policy(dsrc: &Source, client_id: &str) -> Result<RefreshPolicy, Error> {
+    if client_id.is_empty() {
+        return Ok(RefreshPolicy::default())
+    }
+    let client = get_client(dsrc, client_id).await?.ok_or(Error::InvalidRefresh)?;
+    Ok(RefreshPolicy::from(&client))
+}
+
 async fn get_private_key_bytes(dsrc: &Source) -> Result<Vec<u8>, Error> {
     let key = key::get_token_private(&dsrc).await?;
     Ok(key.into_bytes())
 }
 
-async fn get_symmetric_key_bytes(dsrc: &Source) -> Result<Vec<u8>, Error> {
+pub(crate) async fn get_symmetric_key_bytes(dsrc: &Source) -> Result<Vec<u8>, Error> {
     let key = key::get_refresh_symmetric(&dsrc).await?;
     Ok(dec_b64url(key)?)
 }
@@ -104,7 +242,9 @@ fn get_finish_tokens(at: AccessTokenUntimed, it: IdTokenUntimed, utc_now: u64) -
         sub: at.sub,
         iss: at.iss,
         aud: at.aud,
-        scope: at.scope
+        scope: at.scope,
+        auth_time: at.auth_time,
+        amr: at.amr
     };
 
     let it = IdToken { iat: utc_now, exp: utc_now + ACCESS_EXP,
@@ -112,48 +252,78 @@ fn get_finish_tokens(at: AccessTokenUntimed, it: IdTokenUntimed, utc_now: u64) -
         iss: it.iss,
         aud: it.aud,
         auth_time: it.auth_time,
-        nonce: it.nonce
+        nonce: it.nonce,
+        amr: it.amr,
+        acr: it.acr
     };
 
     Ok((at, it))
 }
 
-fn new_refresh(old_refresh: SavedRefreshToken, utc_now: u64) -> Result<(SavedRefreshToken, String), Error> {
+fn new_refresh(old_refresh: SavedRefreshToken, policy: &RefreshPolicy, utc_now: u64) -> Result<(SavedRefreshToken, String), Error> {
     let nonce = rng_urlsafe(16);
+    let exp = policy.next_exp(&old_refresh, utc_now as i32);
 
-    Ok((SavedRefreshToken { nonce: nonce.clone(), iat: utc_now as i32, ..old_refresh }, nonce))
+    Ok((SavedRefreshToken { nonce: nonce.clone(), iat: utc_now as i32, exp, successor_id: None, rotated_at: None, ..old_refresh }, nonce))
 }
 
-async fn new_refresh_save(dsrc: &Source, old_refresh: SavedRefreshToken, utc_now: u64, symmetric_key: &[u8]) -> Result<String, Error> {
-    let (new_saved, nonce) = new_refresh(old_refresh.clone(), utc_now)?;
-    let new_refresh_id = refresh_transaction(dsrc, old_refresh.id, &new_saved).await?;
-    let refresh_token = RefreshToken {
-        id: new_refresh_id,
-        family_id: old_refresh.family_id,
-        nonce
+async fn record_reuse(tx: &mut Tx, reused: &SavedRefreshToken, utc_now: u64, family_revoked: bool) -> Result<(), Error> {
+    let outcome = if family_revoked { "revoked" } else { "successor" };
+    metrics::counter!("tiauth_refresh_reuse_total", 1, "outcome" => outcome);
+    tracing::warn!("refresh token {} of family {} reused, outcome: {}", reused.id, reused.family_id, outcome);
+
+    let event = RefreshReuseEvent {
+        id: 0,
+        family_id: reused.family_id.clone(),
+        user_usph: reused.user_usph.clone(),
+        client_id: reused.client_id.clone(),
+        refresh_id: reused.id,
+        occurred_at: utc_now as i32,
+        family_revoked
     };
+    save_reuse_event(tx, &event).await
+}
+
+/// Recorded once the transaction is committed, as the audit log is written outside of it
+fn refresh_audit_event(kind: AuditKind, refresh: &SavedRefreshToken) -> NewAuditEvent {
+    NewAuditEvent::new(kind)
+        .user(&refresh.user_usph)
+        .client(&refresh.client_id)
+        .detail("family_id", refresh.family_id.as_str())
+        .detail("refresh_id", refresh.id)
+}
 
-    Ok(encrypt_refresh_token(symmetric_key, refresh_token)?)
+fn acr_from_amr(amr: &[String]) -> String {
+    if amr.iter().any(|m| m == AMR_MFA) {
+        ACR_MULTI_FACTOR.to_owned()
+    } else {
+        ACR_SINGLE_FACTOR.to_owned()
+    }
 }
 
-fn id_access_token(sub: &str, iss: &str, aud_access: Vec<String>, aud_id: Vec<String>, scope: &str, auth_time: u64, id_nonce: &str) -> (AccessTokenUntimed, IdTokenUntimed) {
+fn id_access_token(sub: &str, iss: &str, aud_access: Vec<String>, aud_id: Vec<String>, scope: &str, auth_time: u64, id_nonce: &str, amr: Vec<String>) -> (AccessTokenUntimed, IdTokenUntimed) {
     let at = AccessTokenUntimed {
         sub: sub.to_owned(),
         iss: iss.to_owned(),
         aud: aud_access,
-        scope: scope.to_owned()
+        scope: scope.to_owned(),
+        auth_time,
+        amr: amr.clone()
     };
     let it = IdTokenUntimed {
         sub: sub.to_owned(),
         iss: iss.to_owned(),
         aud: aud_id,
         auth_time,
-        nonce: id_nonce.to_owned()
+        nonce: id_nonce.to_owned(),
+        acr: acr_from_amr(&amr),
+        amr
     };
     (at, it)
 }
 
-pub async fn refresh_all_tokens(dsrc: &Source, old_refresh_token: String) -> Result<Tokens, Error> {
+/// The refresh token must have been issued to `client_id`, unless its family predates client binding
+pub async fn refresh_all_tokens(dsrc: &Source, client_id: &str, old_refresh_token: String) -> Result<Tokens, Error> {
     let private_key = get_private_key_bytes(dsrc).await?;
     tracing::debug!("got private key");
     let symmetric_key = get_symmetric_key_bytes(dsrc).await?;
@@ -162,17 +332,18 @@ pub async fn refresh_all_tokens(dsrc: &Source, old_refresh_token: String) -> Res
     tracing::debug!("refresh_token decrypted");
     let utc_now = utc_timestamp();
 
-
-    let saved_refresh = match get_refresh_by_id(&dsrc, old_refresh.id).await {
-        Ok(saved_refresh) => Ok(saved_refresh),
-        Err(error) => match error {
-            Error::NoRow => {
-                delete_family(&dsrc, &old_refresh.family_id).await?;
-                return Err(Error::InvalidRefresh)
-            }
-            e => Err(e)
+    // The token stays locked until the transaction ends, so concurrent refreshes with it are serialized
+    let mut tx = dsrc.db.begin().await?;
+    let saved_refresh = match lock_refresh_by_id(&mut tx, old_refresh.id).await? {
+        Some(saved_refresh) => saved_refresh,
+        None => {
+            // Rotated tokens are kept until they expire, so it was removed together with its family or
+            // presented long after expiring
+            delete_family_tx(&mut tx, &old_refresh.family_id).await?;
+            tx.commit().await?;
+            return Err(Error::InvalidRefresh)
         }
-    }?;
+    };
 
     if saved_refresh.nonce != old_refresh.nonce || saved_refresh.family_id != old_refresh.family_id {
         return Err(Error::InvalidRefresh)
@@ -183,18 +354,60 @@ pub async fn refresh_all_tokens(dsrc: &Source, old_refresh_token: String) -> Res
     if utc_now as i128 > (saved_refresh.exp + GRACE_PERIOD) as i128 {
         return Err(Error::InvalidRefresh)
     }
+    if !saved_refresh.client_id.is_empty() && saved_refresh.client_id != client_id {
+        return Err(Error::InvalidRefresh)
+    }
+    let policy = family_refresh_policy(dsrc, &saved_refresh.client_id).await?;
+    if !policy.allows(&saved_refresh, utc_now as i32) {
+        return Err(Error::InvalidRefresh)
+    }
+
+    let refresh = match saved_refresh.successor_id {
+        None => {
+            let (new_saved, nonce) = new_refresh(saved_refresh.clone(), &policy, utc_now)?;
+            let new_refresh_id = rotate_refresh(&mut tx, saved_refresh.id, &new_saved, utc_now as i32).await?;
+            RefreshToken { id: new_refresh_id, family_id: new_saved.family_id, nonce }
+        }
+        Some(successor_id) => {
+            // Within the reuse window, a client that refreshed twice at once gets the same successor. If
+            // the successor was already rotated itself, the client has moved on and this is a replay.
+            let rotated_at = saved_refresh.rotated_at.unwrap_or(0);
+            let successor = if policy.returns_successor(rotated_at, utc_now as i32) {
+                get_refresh_by_id_tx(&mut tx, successor_id).await?.filter(|s| s.successor_id.is_none())
+            } else {
+                None
+            };
+            record_reuse(&mut tx, &saved_refresh, utc_now, successor.is_none()).await?;
+            match successor {
+                Some(successor) => RefreshToken { id: successor.id, family_id: successor.family_id, nonce: successor.nonce },
+                None => {
+                    delete_family_tx(&mut tx, &saved_refresh.family_id).await?;
+                    tx.commit().await?;
+                    let event = refresh_audit_event(AuditKind::RefreshReuse, &saved_refresh)
+                        .detail("family_revoked", true);
+                    record(dsrc, event).await;
+                    return Err(Error::InvalidRefresh)
+                }
+            }
+        }
+    };
+    tx.commit().await?;
+    let event = match saved_refresh.successor_id {
+        None => refresh_audit_event(AuditKind::TokenRefreshed, &saved_refresh),
+        Some(_) => refresh_audit_event(AuditKind::RefreshReuse, &saved_refresh).detail("family_revoked", false)
+    };
+    record(dsrc, event).await;
 
     let (at, it) = get_finish_tokens_from_save(&saved_refresh, utc_now)?;
 
     let access_token = encode_token(&private_key, &at)?;
     let id_token = encode_token(&private_key, &it)?;
+    let refresh_token = encrypt_refresh_token(&symmetric_key, refresh)?;
 
-    let refresh_token = new_refresh_save(dsrc, saved_refresh, utc_now, &symmetric_key).await?;
-
-    Ok(Tokens { access_token, id_token, refresh_token, returned_scope: at.scope })
+    Ok(Tokens { access_token, id_token, refresh_token: Some(refresh_token), returned_scope: at.scope })
 }
 
-pub async fn new_token_family(dsrc: &Source, user_usph: String, scope: String, id_nonce: String, auth_time: u64) -> Result<Tokens, Error> {
+pub async fn new_token_family(dsrc: &Source, client: &Client, user_usph: String, scope: String, id_nonce: String, auth_time: u64, amr: Vec<String>) -> Result<Tokens, Error> {
     let private_key = key::get_token_private(dsrc).await?;
     let symmetric_key = get_symmetric_key_bytes(dsrc).await?;
     let utc_now = utc_timestamp();
@@ -208,30 +421,41 @@ pub async fn new_token_family(dsrc: &Source, user_usph: String, scope: String, i
         aud_vec,
         &scope,
         auth_time,
-        &id_nonce
+        &id_nonce,
+        amr
     );
 
-    let at_enc = enc_struct(&at)?;
-    let it_enc = enc_struct(&it)?;
-    let family_id = rng_urlsafe(16);
-
-    let refresh_saved = SavedRefreshToken {
-        id: 0,
-        family_id,
-        access_value: at_enc,
-        id_token_value: it_enc,
-        iat: utc_now as i32,
-        exp: utc_now as i32 + REFRESH_EXP,
-        nonce: "".to_string()
-    };
-    let refresh_id = refresh_save(&dsrc, &refresh_saved).await?;
+    let policy = RefreshPolicy::from(client);
+    let refresh_token = if policy.issues_for(&scope) {
+        let at_enc = enc_struct(&at)?;
+        let it_enc = enc_struct(&it)?;
+        let family_id = rng_urlsafe(16);
+
+        let refresh_saved = SavedRefreshToken {
+            id: 0,
+            family_id,
+            user_usph,
+            access_value: at_enc,
+            id_token_value: it_enc,
+            iat: utc_now as i32,
+            exp: policy.first_exp(utc_now as i32),
+            nonce: "".to_string(),
+            client_id: client.client_id.clone(),
+            family_iat: utc_now as i32,
+            successor_id: None,
+            rotated_at: None
+        };
+        let refresh_id = refresh_save(&dsrc, &refresh_saved).await?;
 
-    let refresh = RefreshToken {
-        id: refresh_id,
-        family_id: refresh_saved.family_id,
-        nonce: refresh_saved.nonce
+        let refresh = RefreshToken {
+            id: refresh_id,
+            family_id: refresh_saved.family_id,
+            nonce: refresh_saved.nonce
+        };
+        Some(encrypt_refresh_token(&symmetric_key, refresh)?)
+    } else {
+        None
     };
-    let refresh_token = encrypt_refresh_token(&symmetric_key, refresh)?;
     let (at_fin, it_fin) = get_finish_tokens(at, it, utc_now)?;
 
     let access_token = encode_token(private_key.as_bytes(), &at_fin)?;
@@ -246,6 +470,36 @@ pub fn encode_token<T: Serialize>(private_key: &[u8], claims: &T) -> Result<Stri
     Ok(encode(&header, claims, &encoding_key)?)
 }
 
+pub fn decode_token<T: DeserializeOwned>(public_key: &[u8], token: &str) -> Result<T, Error> {
+    let decoding_key = DecodingKey::from_ed_pem(public_key)?;
+    let mut validation = Validation::new(ED448);
+    validation.set_audience(&AUD);
+    validation.set_issuer(&[ISS]);
+    Ok(decode::<T>(token, &decoding_key, &validation)?.claims)
+}
+
+/// Checks the signature, expiry, issuer and audience of an access token issued by this server
+pub async fn verify_access_token(dsrc: &Source, access_token: &str) -> Result<AccessToken, Error> {
+    let public_key = key::get_token_public(dsrc).await?;
+    decode_token(public_key.as_bytes(), access_token)
+}
+
+/// Deletes all refresh tokens that are past their expiry and grace period, one batch at a time.
+/// Returns the total number deleted.
+pub async fn delete_expired_refresh(dsrc: &Source, batch_size: u64) -> Result<u64, Error> {
+    let cutoff = utc_timestamp() as i32 - GRACE_PERIOD;
+    let mut total = 0;
+    loop {
+        let deleted = delete_expired_batch(dsrc, cutoff, batch_size).await?;
+        metrics::counter!("tiauth_refresh_tokens_expired_deleted_total", deleted);
+        total += deleted;
+        if deleted < batch_size {
+            return Ok(total)
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::auth::keyutil::new_symmetric_keypair;
@@ -258,6 +512,8 @@ mod tests {
             iss: "".to_string(),
             aud: vec![],
             scope: "".to_string(),
+            auth_time: 0,
+            amr: vec![],
             iat: 0,
             exp: 0
         };
@@ -272,6 +528,73 @@ mod tests {
         encode_token(ed25519.as_bytes(), &at).unwrap();
     }
 
+    #[test]
+    fn test_refresh_policy() {
+        let saved = SavedRefreshToken {
+            id: 1,
+            family_id: "".to_string(),
+            user_usph: "".to_string(),
+            access_value: "".to_string(),
+            id_token_value: "".to_string(),
+            iat: 5000,
+            exp: 6000,
+            nonce: "".to_string(),
+            client_id: "".to_string(),
+            family_iat: 1000,
+            successor_id: None,
+            rotated_at: None
+        };
+        let fixed = RefreshPolicy::default();
+        assert_eq!(fixed.next_exp(&saved, 5500), 6000);
+
+        let policy = RefreshPolicy { sliding: true, exp: 3000, max_age: 10000, idle_timeout: Some(1000), ..RefreshPolicy::default() };
+        assert_eq!(policy.next_exp(&saved, 5500), 8500);
+        // Capped by the maximum family age
+        assert_eq!(policy.next_exp(&saved, 9000), 11000);
+        assert!(policy.allows(&saved, 5900));
+        assert!(!policy.allows(&saved, 6100));
+        assert!(!policy.allows(&SavedRefreshToken { iat: 10500, ..saved }, 11100));
+
+        let offline = RefreshPolicy { required_scope: Some("offline_access".to_string()), ..RefreshPolicy::default() };
+        assert!(offline.issues_for("openid offline_access"));
+        assert!(!offline.issues_for("openid"));
+    }
+
+    #[test]
+    fn test_reuse_window() {
+        // Without a reuse window, even a replay in the same second as the rotation is reuse
+        let default = RefreshPolicy::default();
+        assert!(!default.returns_successor(1000, 1000));
+
+        let window = RefreshPolicy { reuse_window: 5, ..RefreshPolicy::default() };
+        assert!(window.returns_successor(1000, 1000));
+        assert!(window.returns_successor(1000, 1004));
+        assert!(!window.returns_successor(1000, 1005));
+    }
+
+    #[test]
+    fn test_access_token_login() {
+        let at = AccessToken {
+            sub: "ab".to_string(),
+            iss: "".to_string(),
+            aud: vec![],
+            scope: "openid account".to_string(),
+            auth_time: 1000,
+            amr: vec![AMR_PASSWORD.to_string(), AMR_OTP.to_string(), AMR_MFA.to_string()],
+            iat: 1000,
+            exp: 4600
+        };
+        assert!(at.has_scope(ACCOUNT_SCOPE));
+        assert!(!at.has_scope(ADMIN_SCOPE));
+        assert!(at.recent_login(300, 1300));
+        assert!(!at.recent_login(300, 1301));
+        assert!(at.multi_factor());
+
+        // Tokens saved before auth_time was added are never recent
+        let untimed: AccessTokenUntimed = serde_json::from_str(r#"{"sub":"ab","iss":"","aud":[],"scope":""}"#).unwrap();
+        assert_eq!(untimed.auth_time, 0);
+    }
+
     #[test]
     fn test_encrypt() {
         let input = "hello".to_owned();
@@ -283,4 +606,4 @@ mod tests {
         let u = String::from_utf8(z).unwrap();
         assert_eq!(input, u)
     }
-}
\ No newline at end of file
+}
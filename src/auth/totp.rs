@@ -0,0 +1,104 @@
+use rand::RngCore;
+use rand::rngs::OsRng;
+use ring::{constant_time, hmac};
+use sha2::{Digest, Sha256};
+use url::form_urlencoded::byte_serialize;
+
+const SECRET_BYTES: usize = 20;
+const DIGITS: u32 = 6;
+const STEP: u64 = 30;
+/// Steps before and after the current one that are still accepted, to allow for clock drift
+const SKEW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// Long enough to cover all steps accepted by `verify_code`
+pub const USED_STEP_EXP: usize = 3 * 60;
+
+pub fn pending_totp_key(user_usph: &str) -> String {
+    format!("totp_enroll:{}", user_usph)
+}
+
+/// Marks a step as used, so each code only works once
+pub fn used_step_key(user_usph: &str, step: u64) -> String {
+    format!("totp_used:{}:{}", user_usph, step)
+}
+
+/// All KV keys of the user that can exist at `utc_now`, including the used steps that have not expired
+pub fn user_kv_keys(user_usph: &str, utc_now: u64) -> Vec<String> {
+    let first = (utc_now.saturating_sub(USED_STEP_EXP as u64) / STEP).saturating_sub(SKEW);
+    let last = utc_now / STEP + SKEW;
+    std::iter::once(pending_totp_key(user_usph))
+        .chain((first..=last).map(|step| used_step_key(user_usph, step)))
+        .collect()
+}
+
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+fn uri_encode(s: &str) -> String {
+    let encoded: String = byte_serialize(s.as_bytes()).collect();
+    encoded.replace('+', "%20")
+}
+
+pub fn otpauth_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    let issuer = uri_encode(issuer);
+    format!("otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer=issuer, account=uri_encode(account), secret=encode_secret(secret), digits=DIGITS, period=STEP)
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+fn totp_code(secret: &[u8], step: u64) -> String {
+    format!("{:0width$}", hotp(secret, step), width=DIGITS as usize)
+}
+
+/// Returns the time step the code belongs to, so that it can be marked as used
+pub fn verify_code(secret: &[u8], code: &str, utc_now: u64) -> Option<u64> {
+    let current = utc_now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| {
+        constant_time::verify_slices_are_equal(totp_code(secret, *step).as_bytes(), code.trim().as_bytes()).is_ok()
+    })
+}
+
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES).map(|_| {
+        let mut code_bytes = [0u8; 6];
+        OsRng.fill_bytes(&mut code_bytes);
+        hex::encode(code_bytes)
+    }).collect()
+}
+
+/// Recovery codes are only stored hashed, they have enough entropy that a salt is not necessary
+pub fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.trim().to_lowercase().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_totp_rfc6238() {
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, 59 / STEP), "287082");
+        assert_eq!(totp_code(secret, 1111111109 / STEP), "081804");
+        assert_eq!(totp_code(secret, 1234567890 / STEP), "005924");
+        assert_eq!(totp_code(secret, 2000000000 / STEP), "279037");
+
+        assert_eq!(verify_code(secret, "287082", 59 + STEP), Some(1));
+        assert_eq!(verify_code(secret, "287082", 59 + 2 * STEP), None);
+    }
+}
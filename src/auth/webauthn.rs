@@ -0,0 +1,78 @@
+use sha2::{Digest, Sha256};
+use webauthn_rs::prelude::{CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid, Webauthn, WebauthnBuilder};
+use crate::config::WebauthnConfig;
+use crate::error::Error;
+use crate::utility::usp_dehex;
+
+pub fn registration_key(user_usph: &str) -> String {
+    format!("webauthn_reg:{}", user_usph)
+}
+
+pub fn new_webauthn(config: &WebauthnConfig) -> Result<Webauthn, Error> {
+    let rp_origin = Url::parse(&config.rp_origin)
+        .map_err(|e| Error::IncorrectField(format!("webauthn rp_origin: {}", e)))?;
+    Ok(WebauthnBuilder::new(&config.rp_id, &rp_origin)?.rp_name(&config.rp_name).build()?)
+}
+
+/// The user handle must be stable across all credentials of a user, so it is derived from the usp_hex
+fn user_handle(user_usph: &str) -> Uuid {
+    let hash = Sha256::digest(user_usph.as_bytes());
+    let mut handle = [0u8; 16];
+    handle.copy_from_slice(&hash[..16]);
+    Uuid::from_bytes(handle)
+}
+
+pub fn start_registration(webauthn: &Webauthn, user_usph: &str, existing: &[Passkey]) -> Result<(CreationChallengeResponse, PasskeyRegistration), Error> {
+    let exclude = existing.iter().map(|p| p.cred_id().clone()).collect();
+    let username = usp_dehex(user_usph);
+    Ok(webauthn.start_passkey_registration(user_handle(user_usph), &username, &username, Some(exclude))?)
+}
+
+pub fn finish_registration(webauthn: &Webauthn, credential: &RegisterPublicKeyCredential, state: &PasskeyRegistration) -> Result<Passkey, Error> {
+    Ok(webauthn.finish_passkey_registration(credential, state)?)
+}
+
+pub fn start_authentication(webauthn: &Webauthn, passkeys: &[Passkey]) -> Result<(RequestChallengeResponse, PasskeyAuthentication), Error> {
+    Ok(webauthn.start_passkey_authentication(passkeys)?)
+}
+
+/// Returns the passkey that was used, with its counter updated, and whether the user was verified
+pub fn finish_authentication(webauthn: &Webauthn, credential: &PublicKeyCredential, state: &PasskeyAuthentication, passkeys: Vec<Passkey>) -> Result<(Passkey, bool), Error> {
+    let result = webauthn.finish_passkey_authentication(credential, state)?;
+    let mut passkey = passkeys.into_iter()
+        .find(|p| p.cred_id() == result.cred_id())
+        .ok_or(Error::IncorrectSecondFactor)?;
+    passkey.update_credential(&result);
+
+    Ok((passkey, result.user_verified()))
+}
+
+#[cfg(test)]
+mod tests {
+    use webauthn_authenticator_rs::WebauthnAuthenticator;
+    use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+    use super::*;
+
+    #[test]
+    fn test_webauthn_ceremonies() {
+        let config = WebauthnConfig::default();
+        let webauthn = new_webauthn(&config).unwrap();
+        let origin = Url::parse(&config.rp_origin).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let (challenge, reg_state) = start_registration(&webauthn, "user", &[]).unwrap();
+        // State is kept in the KV store between requests
+        let reg_state: PasskeyRegistration = serde_json::from_str(&serde_json::to_string(&reg_state).unwrap()).unwrap();
+        let credential = authenticator.do_registration(origin.clone(), challenge).unwrap();
+        let passkey = finish_registration(&webauthn, &credential, &reg_state).unwrap();
+
+        let (challenge, auth_state) = start_authentication(&webauthn, &[passkey.clone()]).unwrap();
+        let auth_state: PasskeyAuthentication = serde_json::from_str(&serde_json::to_string(&auth_state).unwrap()).unwrap();
+        let credential = authenticator.do_authentication(origin, challenge).unwrap();
+        let (used, _) = finish_authentication(&webauthn, &credential, &auth_state, vec![passkey.clone()]).unwrap();
+        assert_eq!(used.cred_id(), passkey.cred_id());
+
+        let (_, other_state) = start_authentication(&webauthn, &[passkey.clone()]).unwrap();
+        assert!(finish_authentication(&webauthn, &credential, &other_state, vec![passkey]).is_err());
+    }
+}
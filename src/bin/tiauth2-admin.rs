@@ -0,0 +1,7 @@
+#[tokio::main]
+async fn main() {
+    if let Err(e) = tiauth2::run_admin().await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,377 @@
+use std::io::BufRead;
+use clap::{Args, Parser, Subcommand};
+use crate::auth::discovery::discovery_document;
+use crate::auth::password::new_password_file;
+use crate::auth::tokens::delete_expired_refresh;
+use crate::config::Config;
+use crate::data::client::{Client, get_client, list_clients, new_client_return_id, upsert_client_row};
+use crate::data::key::{create_keys, list_keys, rotate_key};
+use crate::data::migrate::{migrate, seed_fake_record};
+use crate::data::privacy::{erase_user, export_user};
+use crate::data::refresh::{delete_family, delete_user_families};
+use crate::data::source::Source;
+use crate::data::user;
+use crate::data::user::User;
+use crate::error::Error;
+use crate::mail::valid_address;
+use crate::utility::usp_hex;
+
+/// Administration of a tiauth2 deployment, using the backends from the same configuration as the server
+#[derive(Parser)]
+#[clap(name = "tiauth2-admin")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply pending database migrations, and create the keys and fake record if they do not exist
+    Migrate,
+    #[clap(subcommand)]
+    Keys(KeysCommand),
+    #[clap(subcommand)]
+    Clients(ClientsCommand),
+    #[clap(subcommand)]
+    Users(UsersCommand),
+    /// Revoke a single refresh token family, or all families of a user
+    Revoke {
+        #[clap(long, conflicts_with = "user", required_unless_present = "user")]
+        family: Option<String>,
+        #[clap(long)]
+        user: Option<String>
+    },
+    /// Delete expired refresh tokens
+    Gc {
+        #[clap(long, default_value = "1000")]
+        batch_size: u64
+    },
+    /// Print the OpenID Connect discovery document
+    Discovery
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    List,
+    /// Create the keys that do not exist yet
+    Create,
+    /// Replace a key (0: OPAQUE, 1: token signing, 2: symmetric)
+    Rotate {
+        id: i32,
+        /// Required for keys 0 and 2, rotating them invalidates all passwords or all refresh tokens and
+        /// email links
+        #[clap(long)]
+        force: bool
+    }
+}
+
+#[derive(Subcommand)]
+enum ClientsCommand {
+    List,
+    Add {
+        client_id: String,
+        #[clap(long)]
+        name: Option<String>,
+        #[clap(long = "redirect-uri", required = true)]
+        redirect_uris: Vec<String>,
+        /// Operated by this deployment, see `first-party`
+        #[clap(long)]
+        first_party: bool,
+        #[clap(flatten)]
+        policy: PolicyArgs
+    },
+    /// Change the refresh token policy of a client, options that are not given are left unchanged
+    Policy {
+        client_id: String,
+        #[clap(flatten)]
+        policy: PolicyArgs
+    },
+    /// Mark a client as operated by this deployment, which is required for the admin scope
+    FirstParty {
+        client_id: String,
+        /// Remove the mark instead
+        #[clap(long)]
+        unset: bool
+    },
+    /// Set the origins that can call the token endpoint from a browser, without any the origins of the
+    /// redirect URIs are used
+    Origins {
+        client_id: String,
+        #[clap(long = "origin")]
+        origins: Vec<String>
+    }
+}
+
+#[derive(Args)]
+struct PolicyArgs {
+    #[clap(long)]
+    issue_refresh: Option<bool>,
+    /// Only issue refresh tokens if this scope was granted, an empty value removes the requirement
+    #[clap(long)]
+    refresh_scope: Option<String>,
+    /// Lifetime of a refresh token in seconds
+    #[clap(long)]
+    refresh_exp: Option<i32>,
+    /// Restart the lifetime on every refresh
+    #[clap(long)]
+    sliding: Option<bool>,
+    /// Maximum age of a token family in seconds
+    #[clap(long)]
+    max_age: Option<i32>,
+    /// Seconds a refresh token can go unused, 0 removes the timeout
+    #[clap(long)]
+    idle_timeout: Option<i32>,
+    /// Seconds in which a rotated refresh token returns the same new token, instead of revoking the family
+    #[clap(long)]
+    reuse_window: Option<i32>
+}
+
+impl PolicyArgs {
+    fn apply(self, client: &mut Client) {
+        if let Some(issue_refresh) = self.issue_refresh {
+            client.refresh_issue = issue_refresh;
+        }
+        if let Some(refresh_scope) = self.refresh_scope {
+            client.refresh_scope = Some(refresh_scope).filter(|s| !s.is_empty());
+        }
+        if let Some(refresh_exp) = self.refresh_exp {
+            client.refresh_exp = refresh_exp;
+        }
+        if let Some(sliding) = self.sliding {
+            client.refresh_sliding = sliding;
+        }
+        if let Some(max_age) = self.max_age {
+            client.refresh_max_age = max_age;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            client.refresh_idle_timeout = Some(idle_timeout).filter(|t| *t > 0);
+        }
+        if let Some(reuse_window) = self.reuse_window {
+            client.refresh_reuse_window = reuse_window;
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum UsersCommand {
+    /// Create a user, reading the password from the first line of standard input
+    Create {
+        username: String,
+        /// Considered verified, as it is set by an administrator
+        #[clap(long)]
+        email: Option<String>,
+        #[clap(long)]
+        admin: bool
+    },
+    Disable { username: String },
+    Enable { username: String },
+    Delete { username: String },
+    /// Print everything stored about the user as JSON, also after the user was deleted
+    Export {
+        username: String,
+        /// Write to this file instead of standard output
+        #[clap(long)]
+        output: Option<String>
+    },
+    /// Delete the user and everything tied to them, keeping only pseudonymized audit events
+    Erase {
+        username: String,
+        /// Required, as an erasure cannot be undone
+        #[clap(long)]
+        force: bool
+    }
+}
+
+pub async fn run_admin() -> Result<(), Error> {
+    let cli = Cli::parse();
+    // Needs no config or connections, so it can run anywhere
+    if let Command::Discovery = cli.command {
+        return print_discovery()
+    }
+
+    let config = Config::load()?;
+    let dsrc = Source::new(&config.db_uri, &config.kv_uri).await?
+        .with_audit_file(config.audit.file.as_deref());
+
+    match cli.command {
+        Command::Migrate => {
+            migrate(&dsrc).await?;
+            println!("migrations applied");
+        }
+        Command::Keys(command) => keys(&dsrc, command).await?,
+        Command::Clients(command) => clients(&dsrc, command).await?,
+        Command::Users(command) => users(&dsrc, command).await?,
+        Command::Revoke { family, user } => {
+            if let Some(family_id) = family {
+                delete_family(&dsrc, &family_id).await?;
+            } else if let Some(username) = user {
+                let user = existing_user(&dsrc, &username).await?;
+                delete_user_families(&dsrc, &user.usp_hex).await?;
+            }
+            println!("revoked");
+        }
+        Command::Gc { batch_size } => {
+            let deleted = delete_expired_refresh(&dsrc, batch_size).await?;
+            println!("deleted {} expired refresh tokens", deleted);
+        }
+        Command::Discovery => print_discovery()?
+    }
+
+    Ok(())
+}
+
+fn print_discovery() -> Result<(), Error> {
+    println!("{}", serde_json::to_string_pretty(&discovery_document())?);
+    Ok(())
+}
+
+async fn keys(dsrc: &Source, command: KeysCommand) -> Result<(), Error> {
+    match command {
+        KeysCommand::List => {
+            for key in list_keys(dsrc).await? {
+                println!("{}\t{}\t{} {}", key.id, key.algorithm, key.public_format, key.public_encoding);
+            }
+        }
+        KeysCommand::Create => {
+            create_keys(dsrc).await?;
+            println!("keys created");
+        }
+        KeysCommand::Rotate { id, force } => {
+            if id != 1 && !force {
+                return Err(Error::IncorrectField(format!("rotating key {} requires --force", id)))
+            }
+            let key = rotate_key(dsrc, id).await?;
+            if id == 0 {
+                seed_fake_record(dsrc).await?;
+            }
+            println!("rotated key {} ({})", key.id, key.algorithm);
+        }
+    }
+    Ok(())
+}
+
+/// Browsers send the origin as scheme, host and port only, e.g. `https://app.example.com`
+fn valid_origin(origin: &str) -> bool {
+    url::Url::parse(origin)
+        .map(|url| url.origin().ascii_serialization() == origin)
+        .unwrap_or(false)
+}
+
+async fn clients(dsrc: &Source, command: ClientsCommand) -> Result<(), Error> {
+    match command {
+        ClientsCommand::List => {
+            for client in list_clients(dsrc).await? {
+                println!("{}\t{}\t{}\tfirst_party={}\trefresh: issue={} scope={} exp={} sliding={} max_age={} idle_timeout={} reuse_window={}",
+                         client.client_id, client.name, client.redirect_uris, client.first_party, client.refresh_issue,
+                         client.refresh_scope.as_deref().unwrap_or("-"), client.refresh_exp, client.refresh_sliding,
+                         client.refresh_max_age, client.refresh_idle_timeout.map_or("-".to_string(), |t| t.to_string()),
+                         client.refresh_reuse_window);
+            }
+        }
+        ClientsCommand::Add { client_id, name, redirect_uris, first_party, policy } => {
+            if let Some(uri) = redirect_uris.iter().find(|uri| url::Url::parse(uri).is_err()) {
+                return Err(Error::IncorrectField(format!("invalid redirect URI {}", uri)))
+            }
+            let name = name.unwrap_or_else(|| client_id.clone());
+            let mut client = Client::new(client_id, name, redirect_uris.join(" "));
+            client.first_party = first_party;
+            policy.apply(&mut client);
+            let id = new_client_return_id(dsrc, &client).await?;
+            println!("client {} added with id {}", client.client_id, id);
+        }
+        ClientsCommand::Policy { client_id, policy } => {
+            let mut client = get_client(dsrc, &client_id).await?
+                .ok_or_else(|| Error::IncorrectField(format!("no client {}", client_id)))?;
+            policy.apply(&mut client);
+            upsert_client_row(dsrc, &client).await?;
+            println!("client {} updated", client_id);
+        }
+        ClientsCommand::FirstParty { client_id, unset } => {
+            let mut client = get_client(dsrc, &client_id).await?
+                .ok_or_else(|| Error::IncorrectField(format!("no client {}", client_id)))?;
+            client.first_party = !unset;
+            upsert_client_row(dsrc, &client).await?;
+            println!("client {} first party: {}", client_id, client.first_party);
+        }
+        ClientsCommand::Origins { client_id, origins } => {
+            if let Some(origin) = origins.iter().find(|o| !valid_origin(o)) {
+                return Err(Error::IncorrectField(format!("invalid origin {}", origin)))
+            }
+            let mut client = get_client(dsrc, &client_id).await?
+                .ok_or_else(|| Error::IncorrectField(format!("no client {}", client_id)))?;
+            client.allowed_origins = Some(origins.join(" ")).filter(|o| !o.is_empty());
+            upsert_client_row(dsrc, &client).await?;
+            println!("client {} origins: {}", client_id, client.origins().join(" "));
+        }
+    }
+    Ok(())
+}
+
+async fn existing_user(dsrc: &Source, username: &str) -> Result<User, Error> {
+    user::get_user_by_usph(dsrc, &usp_hex(username)).await?
+        .filter(|u| u.id != 0)
+        .ok_or(Error::UserNotFound)
+}
+
+async fn users(dsrc: &Source, command: UsersCommand) -> Result<(), Error> {
+    match command {
+        UsersCommand::Create { username, email, admin } => {
+            if let Some(email) = &email {
+                if !valid_address(email) {
+                    return Err(Error::IncorrectField("invalid email address".to_owned()))
+                }
+            }
+            let mut password = String::new();
+            std::io::stdin().lock().read_line(&mut password)?;
+            let password = password.trim_end_matches(&['\r', '\n'][..]);
+            if password.is_empty() {
+                return Err(Error::IncorrectField("empty password".to_owned()))
+            }
+
+            let user = User {
+                usp_hex: usp_hex(&username),
+                id: 0,
+                password_file: new_password_file(dsrc, password).await?,
+                email_verified: email.is_some(),
+                email,
+                name: None,
+                admin,
+                disabled: false
+            };
+            let id = user::new_user_return_id(dsrc, &user).await?;
+            println!("user {} created with id {}", username, id);
+        }
+        UsersCommand::Disable { username } => {
+            user::set_disabled(dsrc, existing_user(dsrc, &username).await?, true).await?;
+            println!("user {} disabled", username);
+        }
+        UsersCommand::Enable { username } => {
+            user::set_disabled(dsrc, existing_user(dsrc, &username).await?, false).await?;
+            println!("user {} enabled", username);
+        }
+        UsersCommand::Delete { username } => {
+            user::delete_user(dsrc, &existing_user(dsrc, &username).await?).await?;
+            println!("user {} deleted", username);
+        }
+        UsersCommand::Export { username, output } => {
+            let export = serde_json::to_string_pretty(&export_user(dsrc, &usp_hex(&username)).await?)?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, export)?;
+                    println!("user {} exported to {}", username, path);
+                }
+                None => println!("{}", export)
+            }
+        }
+        UsersCommand::Erase { username, force } => {
+            if !force {
+                return Err(Error::IncorrectField("erasing a user requires --force".to_owned()))
+            }
+            let erasure = erase_user(dsrc, &usp_hex(&username), None).await?;
+            println!("user {} erased{}, {} audit events pseudonymized as {}, {} KV keys deleted", username,
+                     if erasure.user_deleted { "" } else { " (already deleted)" }, erasure.audit_events,
+                     erasure.pseudonym, erasure.kv_keys);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use sea_query::{Expr, Iden, Order, Query, Value as QueryValue};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::data::db::{Database, Row, select_from, Tx, tx_update};
+use crate::data::source::Source;
+use crate::error::Error;
+use crate::utility::utc_timestamp;
+
+#[derive(Iden, Clone, Copy)]
+pub enum AuditEvents {
+    Table,
+    Id,
+    Kind,
+    UserUsph,
+    Ip
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    Login,
+    LoginFailed,
+    MfaCompleted,
+    Register,
+    PasswordChanged,
+    TokenIssued,
+    TokenRefreshed,
+    RefreshReuse,
+    KeyCreated,
+    KeyRotated,
+    ConsentGranted,
+    ConsentRevoked,
+    ProfileUpdated,
+    SessionRevoked,
+    AccountDeleted,
+    UserDisabled,
+    UserEnabled,
+    UserLoggedOut,
+    UserDeleted,
+    UserExported,
+    UserErased
+}
+
+impl AuditKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditKind::Login => "login",
+            AuditKind::LoginFailed => "login_failed",
+            AuditKind::MfaCompleted => "mfa_completed",
+            AuditKind::Register => "register",
+            AuditKind::PasswordChanged => "password_changed",
+            AuditKind::TokenIssued => "token_issued",
+            AuditKind::TokenRefreshed => "token_refreshed",
+            AuditKind::RefreshReuse => "refresh_reuse",
+            AuditKind::KeyCreated => "key_created",
+            AuditKind::KeyRotated => "key_rotated",
+            AuditKind::ConsentGranted => "consent_granted",
+            AuditKind::ConsentRevoked => "consent_revoked",
+            AuditKind::ProfileUpdated => "profile_updated",
+            AuditKind::SessionRevoked => "session_revoked",
+            AuditKind::AccountDeleted => "account_deleted",
+            AuditKind::UserDisabled => "user_disabled",
+            AuditKind::UserEnabled => "user_enabled",
+            AuditKind::UserLoggedOut => "user_logged_out",
+            AuditKind::UserDeleted => "user_deleted",
+            AuditKind::UserExported => "user_exported",
+            AuditKind::UserErased => "user_erased"
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Row, Serialize, Debug)]
+pub struct AuditEvent {
+    pub id: i32,
+    pub kind: String,
+    pub user_usph: Option<String>,
+    pub client_id: Option<String>,
+    pub ip: Option<String>,
+    pub detail: String,
+    pub occurred_at: i32
+}
+
+/// Builds an event, which is recorded with `record`
+pub struct NewAuditEvent {
+    kind: AuditKind,
+    user_usph: Option<String>,
+    client_id: Option<String>,
+    ip: Option<String>,
+    detail: Map<String, Value>
+}
+
+impl NewAuditEvent {
+    pub fn new(kind: AuditKind) -> Self {
+        Self {
+            kind,
+            user_usph: None,
+            client_id: None,
+            ip: None,
+            detail: Map::new()
+        }
+    }
+
+    pub fn user(mut self, user_usph: &str) -> Self {
+        self.user_usph = Some(user_usph.to_owned());
+        self
+    }
+
+    pub fn client(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_owned());
+        self
+    }
+
+    pub fn ip(mut self, ip: &IpAddr) -> Self {
+        self.ip = Some(ip.to_string());
+        self
+    }
+
+    pub fn detail<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        self.detail.insert(key.to_owned(), value.into());
+        self
+    }
+
+    /// The usp_hex of the admin who acted on the user, kept in the detail
+    pub fn actor(self, actor_usph: &str) -> Self {
+        self.detail("actor", actor_usph)
+    }
+}
+
+/// Appends every recorded event as a JSON line, in addition to the table
+#[derive(Clone)]
+pub struct AuditFile {
+    path: Arc<PathBuf>,
+    lock: Arc<Mutex<()>>
+}
+
+impl AuditFile {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: Arc::new(PathBuf::from(path)),
+            lock: Arc::new(Mutex::new(()))
+        }
+    }
+
+    fn append_blocking(&self, line: &[u8]) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&*self.path)?;
+        file.write_all(line)?;
+        Ok(())
+    }
+
+    /// Writes on the blocking thread pool, so that slow disks do not hold up other requests. Waits for
+    /// the write, so an event is never lost when the admin CLI exits right after recording it.
+    async fn append(&self, event: &AuditEvent) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let audit_file = self.clone();
+        tokio::task::spawn_blocking(move || audit_file.append_blocking(&line)).await
+            .map_err(|e| Error::IoError(std::io::Error::new(std::io::ErrorKind::Other, e)))?
+    }
+}
+
+async fn save_event(dsrc: &Source, event: &mut AuditEvent) -> Result<(), Error> {
+    event.id = dsrc.db.insert_return_id(AuditEvents::Table, &*event).await?;
+    if let Some(audit_file) = &dsrc.audit_file {
+        audit_file.append(event).await?;
+    }
+    Ok(())
+}
+
+/// Failing to record an event is logged, but does not fail the action being audited
+pub async fn record(dsrc: &Source, new_event: NewAuditEvent) {
+    let mut event = AuditEvent {
+        id: 0,
+        kind: new_event.kind.as_str().to_owned(),
+        user_usph: new_event.user_usph,
+        client_id: new_event.client_id,
+        ip: new_event.ip,
+        detail: Value::Object(new_event.detail).to_string(),
+        occurred_at: utc_timestamp() as i32
+    };
+    if let Err(e) = save_event(dsrc, &mut event).await {
+        tracing::error!("failed to record audit event {:?}: {:?}", event, e);
+    }
+}
+
+/// Newest events first
+pub async fn list_events(dsrc: &Source, kind: Option<&str>, user_usph: Option<&str>, limit: u64, offset: u64) -> Result<Vec<AuditEvent>, Error> {
+    let mut query = select_from(AuditEvents::Table);
+    query.order_by(AuditEvents::Id, Order::Desc)
+        .limit(limit)
+        .offset(offset);
+    if let Some(kind) = kind {
+        query.and_where(Expr::col(AuditEvents::Kind).eq(kind));
+    }
+    if let Some(user_usph) = user_usph {
+        query.and_where(Expr::col(AuditEvents::UserUsph).eq(user_usph));
+    }
+    dsrc.db.retrieve_all::<AuditEvent>(&query).await
+}
+
+/// Oldest events first
+pub async fn list_user_events(dsrc: &Source, user_usph: &str) -> Result<Vec<AuditEvent>, Error> {
+    let query = select_from(AuditEvents::Table)
+        .and_where(Expr::col(AuditEvents::UserUsph).eq(user_usph))
+        .order_by(AuditEvents::Id, Order::Asc)
+        .to_owned();
+    dsrc.db.retrieve_all::<AuditEvent>(&query).await
+}
+
+/// Replaces the user of their events with the pseudonym and removes the client addresses, the only
+/// change the append-only trigger allows. The pseudonym must start with `erased:`.
+pub async fn tx_pseudonymize_user_events(tx: &mut Tx, user_usph: &str, pseudonym: &str) -> Result<u64, Error> {
+    let query = Query::update()
+        .table(AuditEvents::Table)
+        .values(vec![
+            (AuditEvents::UserUsph, QueryValue::from(pseudonym)),
+            (AuditEvents::Ip, QueryValue::String(None)),
+        ])
+        .and_where(Expr::col(AuditEvents::UserUsph).eq(user_usph))
+        .to_owned();
+    tx_update(tx, &query).await
+}
@@ -0,0 +1,113 @@
+use sea_query::{Expr, Iden, Order};
+use crate::data::db::{Database, Row, select_from};
+use crate::data::source::Source;
+use crate::error::Error;
+
+#[derive(Iden, Clone, Copy)]
+pub enum Clients {
+    Table,
+    Id,
+    ClientId
+}
+
+#[derive(sqlx::FromRow, Row, Debug, Clone)]
+pub struct Client {
+    pub id: i32,
+    pub client_id: String,
+    pub name: String,
+    /// Space-separated, redirect URIs must match one of these exactly
+    pub redirect_uris: String,
+    /// Whether refresh tokens are issued at all
+    pub refresh_issue: bool,
+    /// Only issue refresh tokens if this scope was granted, for example `offline_access`
+    pub refresh_scope: Option<String>,
+    /// Lifetime of a refresh token in seconds
+    pub refresh_exp: i32,
+    /// Restart `refresh_exp` on every refresh, instead of keeping the expiry of the first token
+    pub refresh_sliding: bool,
+    /// Maximum age of a family in seconds, regardless of sliding expiry
+    pub refresh_max_age: i32,
+    /// Reject a refresh token that was not used for this many seconds
+    pub refresh_idle_timeout: Option<i32>,
+    /// Seconds after a refresh in which the old token can be used again, returning the same new token
+    pub refresh_reuse_window: i32,
+    /// Space-separated origins allowed for cross-origin requests, see `Client::origins`
+    pub allowed_origins: Option<String>,
+    /// Operated by this deployment, required for the admin scope
+    pub first_party: bool,
+}
+
+impl Client {
+    /// Uses the same refresh policy as the column defaults
+    pub fn new(client_id: String, name: String, redirect_uris: String) -> Self {
+        Self {
+            id: 0,
+            client_id,
+            name,
+            redirect_uris,
+            refresh_issue: true,
+            refresh_scope: None,
+            refresh_exp: 60 * 60,
+            refresh_sliding: false,
+            refresh_max_age: 30 * 24 * 60 * 60,
+            refresh_idle_timeout: None,
+            refresh_reuse_window: 0,
+            allowed_origins: None,
+            first_party: false
+        }
+    }
+
+    pub fn redirect_uri_allowed(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.split_whitespace().any(|uri| uri == redirect_uri)
+    }
+
+    /// The explicitly allowed origins, or otherwise the origins of the redirect URIs
+    pub fn origins(&self) -> Vec<String> {
+        match &self.allowed_origins {
+            Some(origins) => origins.split_whitespace().map(|o| o.to_owned()).collect(),
+            None => self.redirect_uris.split_whitespace()
+                .filter_map(|uri| url::Url::parse(uri).ok())
+                .map(|url| url.origin())
+                .filter(|origin| origin.is_tuple())
+                .map(|origin| origin.ascii_serialization())
+                .collect()
+        }
+    }
+}
+
+pub async fn get_client(dsrc: &Source, client_id: &str) -> Result<Option<Client>, Error> {
+    let query = select_from(Clients::Table).and_where(Expr::col(Clients::ClientId).eq(client_id)).to_owned();
+    dsrc.db.retrieve_one::<Client>(&query).await
+}
+
+pub async fn list_clients(dsrc: &Source) -> Result<Vec<Client>, Error> {
+    let query = select_from(Clients::Table).order_by(Clients::Id, Order::Asc).to_owned();
+    dsrc.db.retrieve_all::<Client>(&query).await
+}
+
+/// Relies on the unique constraint on `clients.client_id`, returning `Error::ClientExists` if it is taken
+pub async fn new_client_return_id(dsrc: &Source, row: &Client) -> Result<i32, Error> {
+    dsrc.db.insert_return_id(Clients::Table, row).await.map_err(|e| match e {
+        Error::UniqueViolation => Error::ClientExists,
+        e => e
+    })
+}
+
+pub async fn upsert_client_row(dsrc: &Source, row: &Client) -> Result<(), Error> {
+    dsrc.db.upsert_by_id(Clients::Table, row).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+
+    #[test]
+    fn test_origins() {
+        let mut client = Client::new("app".to_string(), "App".to_string(),
+                                     "https://app.example.com/callback http://localhost:8080/cb".to_string());
+        assert_eq!(client.origins(), vec!["https://app.example.com", "http://localhost:8080"]);
+
+        client.allowed_origins = Some("https://other.example.com".to_string());
+        assert_eq!(client.origins(), vec!["https://other.example.com"]);
+    }
+}
@@ -0,0 +1,104 @@
+use sea_query::{Expr, Iden, OnConflict, Order};
+use serde::Serialize;
+use crate::data::db::{Database, delete_from, Row, select_from};
+use crate::data::source::Source;
+use crate::error::Error;
+
+#[derive(Iden, Clone, Copy)]
+pub enum Consents {
+    Table,
+    UserUsph,
+    ClientId,
+    Scope,
+    GrantedAt
+}
+
+#[derive(sqlx::FromRow, Row, Serialize, Debug, Clone)]
+pub struct Consent {
+    pub id: i32,
+    pub user_usph: String,
+    pub client_id: String,
+    /// Space-separated
+    pub scope: String,
+    pub granted_at: i32
+}
+
+/// `openid` only identifies the user, which the consent screen always states, so it is not stored
+fn consent_scopes(scope: &str) -> impl Iterator<Item=&str> {
+    scope.split_whitespace().filter(|s| *s != "openid")
+}
+
+impl Consent {
+    pub fn covers(&self, requested: &str) -> bool {
+        consent_scopes(requested).all(|r| self.scope.split_whitespace().any(|s| s == r))
+    }
+}
+
+pub async fn get_consent(dsrc: &Source, user_usph: &str, client_id: &str) -> Result<Option<Consent>, Error> {
+    let query = select_from(Consents::Table)
+        .and_where(Expr::col(Consents::UserUsph).eq(user_usph))
+        .and_where(Expr::col(Consents::ClientId).eq(client_id))
+        .to_owned();
+    dsrc.db.retrieve_one::<Consent>(&query).await
+}
+
+pub async fn list_consents(dsrc: &Source, user_usph: &str) -> Result<Vec<Consent>, Error> {
+    let query = select_from(Consents::Table)
+        .and_where(Expr::col(Consents::UserUsph).eq(user_usph))
+        .order_by(Consents::ClientId, Order::Asc)
+        .to_owned();
+    dsrc.db.retrieve_all::<Consent>(&query).await
+}
+
+/// Adds the scopes to those the user already agreed to for this client. Concurrent grants do not fail,
+/// but the last one wins, so the user may be asked again for scopes that were only in another grant.
+pub async fn grant_consent(dsrc: &Source, user_usph: &str, client_id: &str, scope: &str, granted_at: i32) -> Result<(), Error> {
+    let existing = get_consent(dsrc, user_usph, client_id).await?;
+    let mut scopes: Vec<&str> = existing.as_ref()
+        .map(|c| c.scope.split_whitespace().collect())
+        .unwrap_or_default();
+    for s in consent_scopes(scope) {
+        if !scopes.contains(&s) {
+            scopes.push(s);
+        }
+    }
+    let consent = Consent {
+        id: 0,
+        user_usph: user_usph.to_owned(),
+        client_id: client_id.to_owned(),
+        scope: scopes.join(" "),
+        granted_at
+    };
+    let on_conflict = OnConflict::columns(vec![Consents::UserUsph, Consents::ClientId])
+        .update_columns(vec![Consents::Scope, Consents::GrantedAt])
+        .to_owned();
+    dsrc.db.insert_on_conflict(Consents::Table, &consent, on_conflict).await
+}
+
+/// Returns whether there was a consent to revoke
+pub async fn revoke_consent(dsrc: &Source, user_usph: &str, client_id: &str) -> Result<bool, Error> {
+    let query = delete_from(Consents::Table)
+        .and_where(Expr::col(Consents::UserUsph).eq(user_usph))
+        .and_where(Expr::col(Consents::ClientId).eq(client_id))
+        .to_owned();
+    Ok(dsrc.db.delete(&query).await? > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Consent;
+
+    #[test]
+    fn test_covers() {
+        let consent = Consent {
+            id: 1,
+            user_usph: "u".to_string(),
+            client_id: "c".to_string(),
+            scope: "profile email".to_string(),
+            granted_at: 0
+        };
+        assert!(consent.covers("openid profile"));
+        assert!(consent.covers(""));
+        assert!(!consent.covers("openid profile offline_access"));
+    }
+}
@@ -1,132 +1,302 @@
+use std::time::Instant;
 use sqlx::{Executor, FromRow, Pool, Postgres, Transaction};
 use async_trait::async_trait;
 use sqlx::postgres::{PgRow};
 use crate::error::Error;
-use sea_query::{Value, Values};
+use sea_query::{Alias, DeleteStatement, Expr, Iden, InsertStatement, OnConflict, PostgresQueryBuilder, Query, SelectStatement, UpdateStatement, Values};
 sea_query::sea_query_driver_postgres!();
 use sea_query_driver_postgres::{bind_query, bind_query_as};
+pub use tiauth2_derive::Row;
 
+/// Tables are `sea_query::Iden` enums, with a `Table` variant for the table itself and one variant per
+/// column, so no identifier is ever taken from input. Filters, ordering and pagination are added to the
+/// statements from `select_from` and `delete_from` with the `sea_query` builders.
 #[async_trait]
 pub trait Database {
-    async fn retrieve_by_id<T>(&self, table: &str, id: i32) -> Result<Option<T>, Error>
+    async fn retrieve_by_id<T, I>(&self, table: I, id: i32) -> Result<Option<T>, Error>
+        where
+            T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+            I: Iden + Send + 'static;
+
+    /// Returns the first row, so filter on a unique column or set an ordering
+    async fn retrieve_one<T>(&self, query: &SelectStatement) -> Result<Option<T>, Error>
         where
             T: for<'r> FromRow<'r, PgRow> + Send + Unpin;
 
-    async fn retrieve_by_unique<T>(&self, table: &str, unique_column: &str, value: Values) -> Result<Option<T>, Error>
+    async fn retrieve_all<T>(&self, query: &SelectStatement) -> Result<Vec<T>, Error>
         where
             T: for<'r> FromRow<'r, PgRow> + Send + Unpin;
 
-    async fn upsert_by_id<R: Row + Send>(&self, table: &str, row: R) -> Result<(), Error>;
+    async fn upsert_by_id<I: Iden + Send + 'static, R: Row + Send>(&self, table: I, row: R) -> Result<(), Error>;
 
-    async fn insert_return_id<T: Row + Send>(&self, table: &str, row: T) -> Result<i32, Error>;
+    /// Inserts the row without its id, so it gets a new one, or on conflict does what `on_conflict` specifies
+    async fn insert_on_conflict<I: Iden + Send + 'static, R: Row + Send>(&self, table: I, row: R, on_conflict: OnConflict) -> Result<(), Error>;
 
-    async fn delete_by_id_required(&self, table: &str, id: i32) -> Result<(), Error>;
+    async fn insert_return_id<I: Iden + Send + 'static, R: Row + Send>(&self, table: I, row: R) -> Result<i32, Error>;
 
-    async fn delete_by_column<V: Send>(&self, table: &str, column: &str, column_val: V) -> Result<(), Error>
-        where
-            sea_query::Value: From<V>;
+    async fn delete_by_id_required<I: Iden + Send + 'static>(&self, table: I, id: i32) -> Result<(), Error>;
+
+    /// Returns the number of deleted rows
+    async fn delete(&self, query: &DeleteStatement) -> Result<u64, Error>;
+
+    /// Returns the number of updated rows
+    async fn update(&self, query: &UpdateStatement) -> Result<u64, Error>;
 
-    async fn delete_insert_return_id_transaction<T: Row + Send>(&self, table: &str, id_delete: i32, row: T) -> Result<i32, Error>;
+    async fn ping(&self) -> Result<(), Error>;
 }
 
 pub struct PSQL {
     pub(crate) pool: Pool<Postgres>
 }
 
-// TODO make a macro for this
+pub type Tx = Transaction<'static, Postgres>;
+
+impl PSQL {
+    async fn execute_insert(&self, query: &InsertStatement) -> Result<(), Error> {
+        let (query, values) = query.build(PostgresQueryBuilder);
+        let start = Instant::now();
+        let result = bind_query(sqlx::query(&query), &values).execute(&self.pool).await;
+        observe("upsert", start);
+        result.map_err(map_unique_violation)?;
+        Ok(())
+    }
+
+    /// Statements in the transaction are run with the `tx_` functions, it is rolled back if dropped
+    /// without committing
+    pub async fn begin(&self) -> Result<Tx, Error> {
+        Ok(self.pool.begin().await?)
+    }
+}
+
+/// Usually derived, see `tiauth2_derive::Row`
 pub trait Row {
-    fn keys(&self, include_id: bool) -> &str;
-    fn vals(&self, include_id: bool) -> &str;
-    fn set(&self) -> &str;
+    fn id_column(&self) -> &'static str;
+    /// Column names, in the same order as `values`
+    fn columns(&self, include_id: bool) -> Vec<&'static str>;
     fn values(&self, include_id: bool) -> Values;
 }
 
+/// Selects all columns of the table
+pub fn select_from<I: Iden + 'static>(table: I) -> SelectStatement {
+    Query::select().expr(Expr::cust("*")).from(table).to_owned()
+}
+
+pub fn delete_from<I: Iden + 'static>(table: I) -> DeleteStatement {
+    Query::delete().from_table(table).to_owned()
+}
+
+/// Records the latency of a statement, including waiting for a pooled connection
+fn observe(op: &'static str, start: Instant) {
+    metrics::histogram!("tiauth_db_query_duration_seconds", start.elapsed().as_secs_f64(), "op" => op);
+}
+
+fn id_column() -> Alias {
+    Alias::new("id")
+}
+
+fn insert_into<I: Iden + 'static, R: Row>(table: I, row: &R, include_id: bool) -> InsertStatement {
+    let columns: Vec<Alias> = row.columns(include_id).into_iter().map(Alias::new).collect();
+    Query::insert()
+        .into_table(table)
+        .columns(columns)
+        .values_panic(row.values(include_id).0)
+        .to_owned()
+}
+
+/// Inserts the row including its id, or updates all other columns if that id exists
+fn upsert_into<I: Iden + 'static, R: Row>(table: I, row: &R) -> InsertStatement {
+    let columns: Vec<Alias> = row.columns(false).into_iter().map(Alias::new).collect();
+    let on_conflict = OnConflict::column(Alias::new(row.id_column()))
+        .update_columns(columns)
+        .to_owned();
+    insert_into(table, row, true).on_conflict(on_conflict).to_owned()
+}
+
 #[async_trait]
 impl Database for PSQL {
-    async fn retrieve_by_id<T>(&self, table: &str, id: i32) -> Result<Option<T>, Error>
+    async fn retrieve_by_id<T, I>(&self, table: I, id: i32) -> Result<Option<T>, Error>
+        where
+            T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+            I: Iden + Send + 'static
+    {
+        let query = select_from(table).and_where(Expr::col(id_column()).eq(id)).to_owned();
+        self.retrieve_one(&query).await
+    }
+
+    async fn retrieve_one<T>(&self, query: &SelectStatement) -> Result<Option<T>, Error>
         where
             T: for<'r> FromRow<'r, PgRow> + Send + Unpin
     {
-        let query = format!("SELECT * FROM {table} WHERE id = $1", table=table);
-        let row: Option<T> = sqlx::query_as(
-            &query
-        )
-            .bind(id)
-            .fetch_optional(&self.pool).await?;
-        Ok(row)
+        let start = Instant::now();
+        let (query, values) = query.build(PostgresQueryBuilder);
+        let row = bind_query_as(sqlx::query_as(&query), &values).fetch_optional(&self.pool).await;
+        observe("retrieve_one", start);
+        Ok(row?)
     }
 
-    async fn retrieve_by_unique<T>(&self, table: &str, unique_column: &str, value: Values) -> Result<Option<T>, Error>
+    async fn retrieve_all<T>(&self, query: &SelectStatement) -> Result<Vec<T>, Error>
         where
             T: for<'r> FromRow<'r, PgRow> + Send + Unpin
     {
-        let query = format!("SELECT * FROM {table} WHERE {column} = $1", table=table, column=unique_column);
-        let row: Option<T> = bind_query_as(sqlx::query_as(&query), &value).fetch_optional(&self.pool).await?;
-        Ok(row)
+        let start = Instant::now();
+        let (query, values) = query.build(PostgresQueryBuilder);
+        let rows = bind_query_as(sqlx::query_as(&query), &values).fetch_all(&self.pool).await;
+        observe("retrieve_all", start);
+        Ok(rows?)
     }
 
-    async fn upsert_by_id<T: Row + Send>(&self, table: &str, row: T) -> Result<(), Error> {
-        let query = format!("INSERT INTO {table} ({keys}) VALUES ({vals}) ON CONFLICT (id)\
-            DO UPDATE SET {set}", table=table, keys=row.keys(true), vals=row.vals(true), set=row.set());
-        let values = row.values(true).to_owned();
-        bind_query(sqlx::query(&query), &values).execute(&self.pool).await?;
-        Ok(())
+    async fn upsert_by_id<I: Iden + Send + 'static, R: Row + Send>(&self, table: I, row: R) -> Result<(), Error> {
+        self.execute_insert(&upsert_into(table, &row)).await
+    }
+
+    async fn insert_on_conflict<I: Iden + Send + 'static, R: Row + Send>(&self, table: I, row: R, on_conflict: OnConflict) -> Result<(), Error> {
+        let query = insert_into(table, &row, false).on_conflict(on_conflict).to_owned();
+        self.execute_insert(&query).await
     }
 
-    async fn insert_return_id<T: Row + Send>(&self, table: &str, row: T) -> Result<i32, Error> {
-        psql_insert_return_id::<&Pool<Postgres>, T>(&self.pool, table, row).await
+    async fn insert_return_id<I: Iden + Send + 'static, R: Row + Send>(&self, table: I, row: R) -> Result<i32, Error> {
+        let start = Instant::now();
+        let result = psql_insert_return_id::<&Pool<Postgres>, _, R>(&self.pool, table, row).await;
+        observe("insert", start);
+        result
     }
 
     /// Returns error if row does not exist
-    async fn delete_by_id_required(&self, table: &str, id: i32) -> Result<(), Error> {
-        psql_delete_by_id_required::<&Pool<Postgres>>(&self.pool, table, id).await
+    async fn delete_by_id_required<I: Iden + Send + 'static>(&self, table: I, id: i32) -> Result<(), Error> {
+        let query = delete_from(table).and_where(Expr::col(id_column()).eq(id)).to_owned();
+        if self.delete(&query).await? == 0 {
+            return Err(Error::NoRow)
+        }
+        Ok(())
     }
 
-    /// Don't allow users to send "column" as it is not injection-protected
     /// If there are no rows, there is no error
-    async fn delete_by_column<V: Send>(&self, table: &str, column: &str, column_val: V) -> Result<(), Error>
-        where
-            sea_query::Value: From<V>
-    {
-        let query = format!("DELETE FROM {table} WHERE {column} = $1", table=table, column=column);
-        let values = Values(vec![Value::from(column_val)]);
-        bind_query(sqlx::query(&query), &values).execute(&self.pool).await?;
-        Ok(())
+    async fn delete(&self, query: &DeleteStatement) -> Result<u64, Error> {
+        let start = Instant::now();
+        let (query, values) = query.build(PostgresQueryBuilder);
+        let result = bind_query(sqlx::query(&query), &values).execute(&self.pool).await;
+        observe("delete", start);
+        Ok(result?.rows_affected())
     }
 
-    async fn delete_insert_return_id_transaction<T: Row + Send>(&self, table: &str, id_delete: i32, row: T) -> Result<i32, Error> {
-        let mut tx = self.pool.begin().await?;
-        psql_delete_by_id_required::<&mut Transaction<Postgres>>(&mut tx, table, id_delete).await?;
-        let id = psql_insert_return_id::<&mut Transaction<Postgres>, T>(&mut tx, table, row).await?;
-        tx.commit().await?;
-        Ok(id)
+    async fn update(&self, query: &UpdateStatement) -> Result<u64, Error> {
+        let start = Instant::now();
+        let (query, values) = query.build(PostgresQueryBuilder);
+        let result = bind_query(sqlx::query(&query), &values).execute(&self.pool).await;
+        observe("update", start);
+        Ok(result?.rows_affected())
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = sqlx::query("SELECT 1").execute(&self.pool).await;
+        observe("ping", start);
+        result?;
+        Ok(())
     }
 }
 
-async fn psql_delete_by_id_required<'a, E>(exec: E, table: &str, id: i32) -> Result<(), Error>
+/// Locks the selected rows until the transaction ends, so concurrent transactions wait for each other
+pub async fn tx_retrieve_one_for_update<T>(tx: &mut Tx, query: &SelectStatement) -> Result<Option<T>, Error>
     where
-        E: Executor<'a, Database=Postgres>
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
 {
-    let query = format!("WITH deleted as (DELETE FROM {table} WHERE id = $1 returning 1)
-                                SELECT count(*) from deleted;", table=table);
-    let count: (i64,) = sqlx::query_as(
-        &query
-    )
-        .bind(id).fetch_one(exec)
-        .await?;
-    if count.0 == 0 {
-        return Err(Error::NoRow)
-    }
+    let (query, values) = query.build(PostgresQueryBuilder);
+    let query = format!("{} FOR UPDATE", query);
+    let row: Option<T> = bind_query_as(sqlx::query_as(&query), &values).fetch_optional(&mut *tx).await?;
+    Ok(row)
+}
+
+pub async fn tx_retrieve_one<T>(tx: &mut Tx, query: &SelectStatement) -> Result<Option<T>, Error>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    let (query, values) = query.build(PostgresQueryBuilder);
+    let row: Option<T> = bind_query_as(sqlx::query_as(&query), &values).fetch_optional(&mut *tx).await?;
+    Ok(row)
+}
+
+/// Locks the selected rows until the transaction ends, see `tx_retrieve_one_for_update`
+pub async fn tx_retrieve_all_for_update<T>(tx: &mut Tx, query: &SelectStatement) -> Result<Vec<T>, Error>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin
+{
+    let (query, values) = query.build(PostgresQueryBuilder);
+    let query = format!("{} FOR UPDATE", query);
+    let rows: Vec<T> = bind_query_as(sqlx::query_as(&query), &values).fetch_all(&mut *tx).await?;
+    Ok(rows)
+}
+
+pub async fn tx_upsert_by_id<I: Iden + 'static, R: Row>(tx: &mut Tx, table: I, row: &R) -> Result<(), Error> {
+    let (query, values) = upsert_into(table, row).build(PostgresQueryBuilder);
+    bind_query(sqlx::query(&query), &values).execute(&mut *tx).await.map_err(map_unique_violation)?;
     Ok(())
 }
 
-async fn psql_insert_return_id<'a, E, T: Row + Send>(exec: E, table: &str, row: T) -> Result<i32, Error>
+pub async fn tx_insert_return_id<I: Iden + 'static, R: Row + Send>(tx: &mut Tx, table: I, row: R) -> Result<i32, Error> {
+    psql_insert_return_id::<&mut Tx, I, R>(tx, table, row).await
+}
+
+/// Returns the number of updated rows
+pub async fn tx_update(tx: &mut Tx, query: &UpdateStatement) -> Result<u64, Error> {
+    let (query, values) = query.build(PostgresQueryBuilder);
+    let result = bind_query(sqlx::query(&query), &values).execute(&mut *tx).await?;
+    Ok(result.rows_affected())
+}
+
+/// Returns the number of deleted rows
+pub async fn tx_delete(tx: &mut Tx, query: &DeleteStatement) -> Result<u64, Error> {
+    let (query, values) = query.build(PostgresQueryBuilder);
+    let result = bind_query(sqlx::query(&query), &values).execute(&mut *tx).await?;
+    Ok(result.rows_affected())
+}
+
+/// Returns `Error::UniqueViolation` if the row conflicts with a unique constraint
+async fn psql_insert_return_id<'a, E, I: Iden + 'static, T: Row + Send>(exec: E, table: I, row: T) -> Result<i32, Error>
     where
         E: Executor<'a, Database=Postgres>
 {
-    let query = format!("INSERT INTO {table} ({keys}) VALUES ({vals}) RETURNING (id)",
-                        table=table, keys=row.keys(false), vals=row.vals(false));
-    let values = row.values(false).to_owned();
-    let id: (i32,) = bind_query_as(sqlx::query_as(&query), &values).fetch_one(exec).await?;
+    let returning = Query::select().column(Alias::new(row.id_column())).to_owned();
+    let query = insert_into(table, &row, false).returning(returning).to_owned();
+    let (query, values) = query.build(PostgresQueryBuilder);
+    let id: (i32,) = bind_query_as(sqlx::query_as(&query), &values).fetch_one(exec).await
+        .map_err(map_unique_violation)?;
     Ok(id.0)
-}
\ No newline at end of file
+}
+
+const PG_UNIQUE_VIOLATION: &str = "23505";
+
+fn map_unique_violation(err: sqlx::Error) -> Error {
+    match &err {
+        sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some(PG_UNIQUE_VIOLATION) => {
+            Error::UniqueViolation
+        }
+        _ => Error::DbError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Row;
+
+    #[derive(Row)]
+    struct TestRow {
+        name: String,
+        #[row(id)]
+        key: i32,
+        #[row(skip)]
+        cached: bool,
+        count: i32
+    }
+
+    #[test]
+    fn test_derive_row() {
+        let row = TestRow { name: "a".to_string(), key: 3, cached: false, count: 5 };
+        let row = &row;
+        assert_eq!(row.id_column(), "key");
+        assert_eq!(row.columns(true), vec!["key", "name", "count"]);
+        assert_eq!(row.columns(false), vec!["name", "count"]);
+        assert_eq!(row.values(true).0.len(), 3);
+        assert_eq!(row.values(false).0.len(), 2);
+    }
+}
@@ -1,10 +1,19 @@
-use sea_query::{Value, Values};
-use crate::data::db::{Database, Row};
+use sea_query::{Iden, Order};
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
+use crate::data::db::{Database, Row, select_from, tx_upsert_by_id};
 use crate::data::source::Source;
+use crate::data::totp::tx_reencrypt_totp_secrets;
 use crate::error::Error;
+use crate::utility::dec_b64url;
 use crate::auth::keyutil::{new_curve25519_keypair, new_ed448_keypair, new_symmetric_keypair};
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(Iden, Clone, Copy)]
+pub enum Keys {
+    Table,
+    Id
+}
+
+#[derive(sqlx::FromRow, Row, Debug, Clone)]
 pub struct Key {
     pub id: i32,
     pub algorithm: String,
@@ -16,48 +25,8 @@ pub struct Key {
     pub private_encoding: String,
 }
 
-impl<'a> Row for &'a Key {
-    fn keys(&self, include_id: bool) -> &str {
-        if include_id {
-            "id, algorithm, public, private, public_format, public_encoding, private_format, private_encoding"
-        } else {
-            "algorithm, public, private, public_format, public_encoding, private_format, private_encoding"
-        }
-    }
-
-    fn vals(&self, include_id: bool) -> &str {
-        if include_id {
-            "$1, $2, $3, $4, $5, $6, $7, $8"
-        } else {
-            "$1, $2, $3, $4, $5, $6, $7"
-        }
-    }
-
-    fn set(&self) -> &str {
-        "id = $1, algorithm = $2, public = $3, private = $4, public_format = $5, public_encoding = $6, private_format = $7, private_encoding = $8"
-    }
-
-    fn values(&self, include_id: bool) -> Values {
-        let mut val_vec = vec![
-            Value::from(self.algorithm.clone()),
-            Value::from(self.public.clone()),
-            Value::from(self.private.clone()),
-            Value::from(self.public_format.clone()),
-            Value::from(self.public_encoding.clone()),
-            Value::from(self.private_format.clone()),
-            Value::from(self.private_encoding.clone()),
-        ];
-
-        if include_id {
-            val_vec.insert(0, Value::from(self.id));
-        }
-
-        Values(val_vec)
-    }
-}
-
 async fn get_key_row(dsrc: &Source, id: i32) -> Result<Option<Key>, Error> {
-    dsrc.db.retrieve_by_id::<Key>("keys", id).await
+    dsrc.db.retrieve_by_id::<Key, _>(Keys::Table, id).await
 }
 
 pub async fn get_opaque_private(dsrc: &Source) -> Result<String, Error> {
@@ -72,6 +41,10 @@ pub async fn get_token_private(dsrc: &Source) -> Result<String, Error> {
     Ok(get_token_key(dsrc).await?.private)
 }
 
+pub async fn get_token_public(dsrc: &Source) -> Result<String, Error> {
+    Ok(get_token_key(dsrc).await?.public)
+}
+
 pub async fn get_refresh_symmetric(dsrc: &Source) -> Result<String, Error> {
     Ok(get_symmetric_key(dsrc).await?.private)
 }
@@ -89,11 +62,18 @@ async fn get_or_create_key(dsrc: &Source, id: i32, create_fn: fn() -> Key) -> Re
     });
     if empty {
         upsert_key_row(dsrc, &key).await?;
+        record(dsrc, key_event(AuditKind::KeyCreated, &key)).await;
     }
 
     Ok(key)
 }
 
+fn key_event(kind: AuditKind, key: &Key) -> NewAuditEvent {
+    NewAuditEvent::new(kind)
+        .detail("key_id", key.id)
+        .detail("algorithm", key.algorithm.as_str())
+}
+
 async fn get_token_key(dsrc: &Source) -> Result<Key, Error> {
     get_or_create_key(dsrc, 1, new_ed448_keypair).await
 }
@@ -102,6 +82,46 @@ async fn get_symmetric_key(dsrc: &Source) -> Result<Key, Error> {
     get_or_create_key(dsrc, 2, new_symmetric_keypair).await
 }
 
+/// Creates the keys that do not exist yet
+pub async fn create_keys(dsrc: &Source) -> Result<(), Error> {
+    get_opaque_key(dsrc).await?;
+    get_token_key(dsrc).await?;
+    get_symmetric_key(dsrc).await?;
+    Ok(())
+}
+
+pub async fn list_keys(dsrc: &Source) -> Result<Vec<Key>, Error> {
+    let query = select_from(Keys::Table).order_by(Keys::Id, Order::Asc).to_owned();
+    dsrc.db.retrieve_all::<Key>(&query).await
+}
+
+/// Replaces the key with a newly generated one. Everything protected by the old key can no longer be
+/// used, so rotating the OPAQUE key (0) invalidates all password files and rotating the symmetric key
+/// (2) all refresh tokens, email links and TOTP enrollments that are not confirmed yet. Stored TOTP
+/// secrets are re-encrypted with the new symmetric key in the same transaction.
+pub async fn rotate_key(dsrc: &Source, id: i32) -> Result<Key, Error> {
+    let key = match id {
+        0 => new_curve25519_keypair(),
+        1 => new_ed448_keypair(),
+        2 => new_symmetric_keypair(),
+        _ => return Err(Error::IncorrectField(format!("no key with id {}", id)))
+    };
+    if id == 2 {
+        let old_key = dec_b64url(get_symmetric_key(dsrc).await?.private)?;
+        let new_key = dec_b64url(&key.private)?;
+        let mut tx = dsrc.db.begin().await?;
+        let reencrypted = tx_reencrypt_totp_secrets(&mut tx, &old_key, &new_key).await?;
+        tx_upsert_by_id(&mut tx, Keys::Table, &key).await?;
+        tx.commit().await?;
+        tracing::info!("re-encrypted {} totp secrets", reencrypted);
+    } else {
+        upsert_key_row(dsrc, &key).await?;
+    }
+    record(dsrc, key_event(AuditKind::KeyRotated, &key)).await;
+
+    Ok(key)
+}
+
 pub async fn upsert_key_row(dsrc: &Source, row: &Key) -> Result<(), Error> {
-    dsrc.db.upsert_by_id("keys", row).await
+    dsrc.db.upsert_by_id(Keys::Table, row).await
 }
\ No newline at end of file
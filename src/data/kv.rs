@@ -1,9 +1,11 @@
+use std::time::Instant;
 use redis::aio::ConnectionManager;
 use serde::{Serialize};
 use serde_json::{from_str as serde_from_j_str, to_string as serde_to_j_str};
 use crate::error::Error;
 use async_trait::async_trait;
-use redis::{FromRedisValue, Value};
+use redis::{FromRedisValue, Script, Value};
+use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 
 pub struct Redis {
@@ -15,12 +17,94 @@ pub trait KeyValue {
     async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error>;
 
     async fn store_json<T: Serialize + Sync>(&self, key: &str, json: &T, expire: usize) -> Result<(), Error>;
+
+    /// Returns whether the key existed, so it can be used to consume single-use values
+    async fn delete(&self, key: &str) -> Result<bool, Error>;
+
+    async fn get_counter(&self, key: &str) -> Result<Option<i64>, Error>;
+
+    /// Atomically increments a counter, returning the new value. The expiry is reset on every call.
+    async fn incr(&self, key: &str, expire: usize) -> Result<i64, Error>;
+
+    /// Takes a token from the bucket at key, returning 0 if one was available, or otherwise the
+    /// number of seconds until there will be one
+    async fn take_token(&self, key: &str, capacity: u32, refill_per_sec: f64) -> Result<u64, Error>;
+
+    /// Returns the seconds to wait before the next attempt at key, where `waits[n]` is the wait after
+    /// `n` attempts and the last entry applies to any more. If no wait is needed and `count` is set,
+    /// the attempt is counted in the same step, so concurrent attempts cannot all pass the check.
+    async fn take_attempt(&self, key: &str, waits: &[u64], window: usize, count: bool) -> Result<u64, Error>;
+
+    /// Uncounts an attempt taken with `take_attempt`
+    async fn refund_attempt(&self, key: &str) -> Result<(), Error>;
+
+    /// Also fails if the JSON commands are not available
+    async fn ping(&self) -> Result<(), Error>;
+}
+
+/// Uses the Redis clock, so that buckets are consistent between replicas
+static TOKEN_BUCKET: Lazy<Script> = Lazy::new(|| Script::new(r"
+    local capacity = tonumber(ARGV[1])
+    local refill = tonumber(ARGV[2])
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+
+    local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+    local tokens = tonumber(bucket[1]) or capacity
+    local ts = tonumber(bucket[2]) or now
+    tokens = math.min(capacity, tokens + math.max(0, now - ts) * refill)
+
+    local retry_after = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+    else
+        retry_after = math.ceil((1 - tokens) / refill)
+    end
+    redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+    redis.call('EXPIRE', KEYS[1], math.ceil(capacity / refill) + 1)
+    return retry_after
+"));
+
+/// Also uses the Redis clock, the time of the last attempt is stored with the count
+static ATTEMPT: Lazy<Script> = Lazy::new(|| Script::new(r"
+    local count = ARGV[1] == '1'
+    local window = tonumber(ARGV[2])
+    local now = tonumber(redis.call('TIME')[1])
+
+    local state = redis.call('HMGET', KEYS[1], 'attempts', 'last')
+    local attempts = tonumber(state[1]) or 0
+    local last = tonumber(state[2]) or 0
+    local wait = tonumber(ARGV[math.min(attempts + 3, #ARGV)])
+
+    local retry_after = math.max(0, last + wait - now)
+    if retry_after > 0 then
+        return retry_after
+    end
+    if count then
+        redis.call('HSET', KEYS[1], 'attempts', tostring(attempts + 1), 'last', tostring(now))
+        redis.call('EXPIRE', KEYS[1], window)
+    end
+    return 0
+"));
+
+static REFUND_ATTEMPT: Lazy<Script> = Lazy::new(|| Script::new(r"
+    if (tonumber(redis.call('HGET', KEYS[1], 'attempts')) or 0) > 0 then
+        redis.call('HINCRBY', KEYS[1], 'attempts', -1)
+    end
+    return 0
+"));
+
+fn observe(op: &'static str, start: Instant) {
+    metrics::histogram!("tiauth_kv_command_duration_seconds", start.elapsed().as_secs_f64(), "op" => op);
 }
 
 #[async_trait]
 impl KeyValue for Redis {
     async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
-        let val: Value = redis::cmd("JSON.GET").arg(key).query_async(&mut self.conn_manager.clone()).await?;
+        let start = Instant::now();
+        let val: Result<Value, _> = redis::cmd("JSON.GET").arg(key).query_async(&mut self.conn_manager.clone()).await;
+        observe("get_json", start);
+        let val = val?;
         match val {
             Value::Nil => Ok(None),
             _ => {
@@ -33,10 +117,72 @@ impl KeyValue for Redis {
     async fn store_json<T: Serialize + Sync>(&self, key: &str, json: &T, expire: usize) -> Result<(), Error> {
         let json_str = serde_to_j_str(json)?;
 
-        let _: () = redis::pipe()
+        // EXPIRE has no effect on a key that does not exist yet, so it has to come after JSON.SET
+        let start = Instant::now();
+        let result: Result<(), _> = redis::pipe()
+            .cmd("JSON.SET").arg(key).arg(".").arg(&json_str).ignore()
             .expire(key, expire).ignore()
-            .cmd("JSON.SET").arg(key).arg(".").arg(&json_str)
-            .query_async(&mut self.conn_manager.clone()).await?;
+            .query_async(&mut self.conn_manager.clone()).await;
+        observe("store_json", start);
+        Ok(result?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, Error> {
+        let start = Instant::now();
+        let deleted: Result<i64, _> = redis::cmd("DEL").arg(key).query_async(&mut self.conn_manager.clone()).await;
+        observe("delete", start);
+        Ok(deleted? > 0)
+    }
+
+    async fn get_counter(&self, key: &str) -> Result<Option<i64>, Error> {
+        let start = Instant::now();
+        let count: Result<Option<i64>, _> = redis::cmd("GET").arg(key).query_async(&mut self.conn_manager.clone()).await;
+        observe("get_counter", start);
+        Ok(count?)
+    }
+
+    async fn incr(&self, key: &str, expire: usize) -> Result<i64, Error> {
+        let start = Instant::now();
+        let result: Result<(i64,), _> = redis::pipe().atomic()
+            .incr(key, 1)
+            .expire(key, expire).ignore()
+            .query_async(&mut self.conn_manager.clone()).await;
+        observe("incr", start);
+        Ok(result?.0)
+    }
+
+    async fn take_token(&self, key: &str, capacity: u32, refill_per_sec: f64) -> Result<u64, Error> {
+        let start = Instant::now();
+        let retry_after: Result<u64, _> = TOKEN_BUCKET.key(key).arg(capacity).arg(refill_per_sec)
+            .invoke_async(&mut self.conn_manager.clone()).await;
+        observe("take_token", start);
+        Ok(retry_after?)
+    }
+
+    async fn take_attempt(&self, key: &str, waits: &[u64], window: usize, count: bool) -> Result<u64, Error> {
+        let start = Instant::now();
+        let retry_after: Result<u64, _> = ATTEMPT.key(key).arg(if count { 1 } else { 0 }).arg(window).arg(waits)
+            .invoke_async(&mut self.conn_manager.clone()).await;
+        observe("take_attempt", start);
+        Ok(retry_after?)
+    }
+
+    async fn refund_attempt(&self, key: &str) -> Result<(), Error> {
+        let start = Instant::now();
+        let result: Result<(), _> = REFUND_ATTEMPT.key(key).invoke_async(&mut self.conn_manager.clone()).await;
+        observe("refund_attempt", start);
+        Ok(result?)
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        // JSON.TYPE on a missing key returns nil, but is an unknown command without RedisJSON
+        let result: Result<(Value,), _> = redis::pipe()
+            .cmd("PING").ignore()
+            .cmd("JSON.TYPE").arg("tiauth:ping")
+            .query_async(&mut self.conn_manager.clone()).await;
+        observe("ping", start);
+        result?;
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,39 @@
+use sqlx::migrate::Migrator;
+use crate::auth::password::new_password_file;
+use crate::data::key::create_keys;
+use crate::data::source::Source;
+use crate::data::user;
+use crate::data::user::User;
+use crate::error::Error;
+use crate::utility::rng_urlsafe;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// `usp_hex` never produces a lone "~fa", as 0xfa does not occur in UTF-8, so no username can take this
+const FAKE_RECORD_USPH: &str = "~fakerecord";
+
+/// Applies pending migrations, then creates the keys and the fake record if they do not exist yet
+pub async fn migrate(dsrc: &Source) -> Result<(), Error> {
+    MIGRATOR.run(&dsrc.db.pool).await?;
+    create_keys(dsrc).await?;
+    if user::get_user_by_id(dsrc, 0).await?.is_none() {
+        seed_fake_record(dsrc).await?;
+    }
+    Ok(())
+}
+
+/// The fake record (id 0) is given to `start_login` for unknown users. It must be registered with the
+/// current OPAQUE key, otherwise their login fails differently from one with a wrong password.
+pub async fn seed_fake_record(dsrc: &Source) -> Result<(), Error> {
+    let fake_record = User {
+        usp_hex: FAKE_RECORD_USPH.to_owned(),
+        id: 0,
+        password_file: new_password_file(dsrc, &rng_urlsafe(32)).await?,
+        email: None,
+        email_verified: false,
+        name: None,
+        admin: false,
+        disabled: false
+    };
+    user::upsert_user_row(dsrc, &fake_record).await
+}
@@ -1,8 +1,15 @@
 pub(crate) mod source;
-mod db;
+pub(crate) mod db;
 pub mod user;
 pub(crate) mod kv;
 pub mod key;
+pub mod client;
+pub mod consent;
+pub mod migrate;
+pub mod audit;
+pub mod privacy;
 pub mod refresh;
+pub mod totp;
+pub mod webauthn;
 
 pub use key::Key;
\ No newline at end of file
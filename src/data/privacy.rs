@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::auth::{throttle, totp};
+use crate::auth::webauthn::registration_key;
+use crate::data::audit::{AuditEvent, AuditKind, list_user_events, NewAuditEvent, record, tx_pseudonymize_user_events};
+use crate::data::consent::{Consent, list_consents};
+use crate::data::db::tx_delete;
+use crate::data::kv::KeyValue;
+use crate::data::refresh::{list_user_refresh, list_user_reuse_events, RefreshReuseEvent, SavedRefreshToken};
+use crate::data::source::Source;
+use crate::data::totp::get_totp_by_usph;
+use crate::data::user;
+use crate::data::user::{User, user_deletes};
+use crate::data::webauthn::get_passkeys_by_usph;
+use crate::error::Error;
+use crate::utility::{rng_urlsafe, usp_dehex, utc_timestamp};
+
+/// The user row without the password file
+#[derive(Serialize, Debug)]
+pub struct ExportedUser {
+    pub id: i32,
+    pub username: String,
+    pub usp_hex: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub admin: bool,
+    pub disabled: bool,
+    pub totp_enrolled: bool,
+    pub passkeys: usize
+}
+
+/// A family that has not expired, with its current token
+#[derive(Serialize, Debug)]
+pub struct ExportedSession {
+    pub family_id: String,
+    pub client_id: String,
+    pub issued_at: i32,
+    pub refreshed_at: i32,
+    pub expires_at: i32
+}
+
+/// Token values are left out, they are credentials rather than data about the user
+#[derive(Serialize, Debug)]
+pub struct ExportedRefreshToken {
+    pub id: i32,
+    pub iat: i32,
+    pub exp: i32,
+    pub successor_id: Option<i32>,
+    pub rotated_at: Option<i32>
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedFamily {
+    pub family_id: String,
+    pub client_id: String,
+    pub issued_at: i32,
+    pub tokens: Vec<ExportedRefreshToken>
+}
+
+/// Everything stored about a user, for a data subject access request
+#[derive(Serialize, Debug)]
+pub struct UserExport {
+    pub exported_at: u64,
+    /// `None` if the user was deleted, while other data may remain
+    pub user: Option<ExportedUser>,
+    pub consents: Vec<Consent>,
+    pub sessions: Vec<ExportedSession>,
+    pub refresh_families: Vec<ExportedFamily>,
+    pub refresh_reuse_events: Vec<RefreshReuseEvent>,
+    pub audit_events: Vec<AuditEvent>
+}
+
+#[derive(Serialize, Debug)]
+pub struct Erasure {
+    /// Replaces the usp_hex in the audit events of the user
+    pub pseudonym: String,
+    pub user_deleted: bool,
+    pub audit_events: u64,
+    pub kv_keys: u64
+}
+
+async fn export_user_row(dsrc: &Source, user: User) -> Result<ExportedUser, Error> {
+    let totp_enrolled = get_totp_by_usph(dsrc, &user.usp_hex).await?.is_some();
+    let passkeys = get_passkeys_by_usph(dsrc, &user.usp_hex).await?.len();
+    Ok(ExportedUser {
+        id: user.id,
+        username: usp_dehex(&user.usp_hex),
+        usp_hex: user.usp_hex,
+        name: user.name,
+        email: user.email,
+        email_verified: user.email_verified,
+        admin: user.admin,
+        disabled: user.disabled,
+        totp_enrolled,
+        passkeys
+    })
+}
+
+fn group_families(tokens: Vec<SavedRefreshToken>, now: i32) -> (Vec<ExportedSession>, Vec<ExportedFamily>) {
+    let mut sessions = Vec::new();
+    let mut families: BTreeMap<String, ExportedFamily> = BTreeMap::new();
+    for token in tokens {
+        if token.successor_id.is_none() && token.exp >= now {
+            sessions.push(ExportedSession {
+                family_id: token.family_id.clone(),
+                client_id: token.client_id.clone(),
+                issued_at: token.family_iat,
+                refreshed_at: token.iat,
+                expires_at: token.exp
+            });
+        }
+        let family = families.entry(token.family_id.clone()).or_insert_with(|| ExportedFamily {
+            family_id: token.family_id,
+            client_id: token.client_id,
+            issued_at: token.family_iat,
+            tokens: Vec::new()
+        });
+        family.tokens.push(ExportedRefreshToken {
+            id: token.id,
+            iat: token.iat,
+            exp: token.exp,
+            successor_id: token.successor_id,
+            rotated_at: token.rotated_at
+        });
+    }
+    let mut families: Vec<ExportedFamily> = families.into_values().collect();
+    families.sort_by_key(|f| f.issued_at);
+    (sessions, families)
+}
+
+pub async fn export_user(dsrc: &Source, user_usph: &str) -> Result<UserExport, Error> {
+    let now = utc_timestamp();
+    let user = match user::get_user_by_usph(dsrc, user_usph).await? {
+        Some(user) => Some(export_user_row(dsrc, user).await?),
+        None => None
+    };
+    let (sessions, refresh_families) = group_families(list_user_refresh(dsrc, user_usph).await?, now as i32);
+
+    Ok(UserExport {
+        exported_at: now,
+        user,
+        consents: list_consents(dsrc, user_usph).await?,
+        sessions,
+        refresh_families,
+        refresh_reuse_events: list_user_reuse_events(dsrc, user_usph).await?,
+        audit_events: list_user_events(dsrc, user_usph).await?
+    })
+}
+
+/// Exact keys, never patterns, as a username can equal a segment of other keys, such as `user` in
+/// `login_throttle:user:{usp_hex}`
+fn user_kv_keys(user_usph: &str, utc_now: u64) -> Vec<String> {
+    let mut keys = throttle::user_kv_keys(user_usph);
+    keys.extend(totp::user_kv_keys(user_usph, utc_now));
+    keys.push(registration_key(user_usph));
+    keys
+}
+
+/// Deletes the user and everything tied to their usp_hex, except audit events, which are kept under a
+/// random pseudonym without client addresses. The deletes and the pseudonymization are one transaction.
+/// Other KV entries that mention the user, such as pending logins, are not keyed by the user and expire
+/// within minutes.
+///
+/// `actor_usph` is the admin who requested the erasure, if it was not done with the admin CLI.
+///
+/// Refused while audit events are also written to `audit.file`, as lines already written there or
+/// shipped from it cannot be pseudonymized here.
+pub async fn erase_user(dsrc: &Source, user_usph: &str, actor_usph: Option<&str>) -> Result<Erasure, Error> {
+    if dsrc.audit_file.is_some() {
+        return Err(Error::ErasureUnavailable("audit.file is set".to_owned()))
+    }
+
+    let existing = user::get_user_by_usph(dsrc, user_usph).await?;
+    let pseudonym = format!("erased:{}", rng_urlsafe(16));
+    let mut tx = dsrc.db.begin().await?;
+    for query in user_deletes(user_usph) {
+        tx_delete(&mut tx, &query).await?;
+    }
+    let audit_events = tx_pseudonymize_user_events(&mut tx, user_usph, &pseudonym).await?;
+    tx.commit().await?;
+
+    let mut kv_keys = 0;
+    for key in user_kv_keys(user_usph, utc_timestamp()) {
+        if dsrc.kv.delete(&key).await? {
+            kv_keys += 1;
+        }
+    }
+    let mut event = NewAuditEvent::new(AuditKind::UserErased).user(&pseudonym);
+    if let Some(actor_usph) = actor_usph {
+        event = event.actor(actor_usph);
+    }
+    record(dsrc, event).await;
+
+    Ok(Erasure {
+        pseudonym,
+        user_deleted: existing.is_some(),
+        audit_events,
+        kv_keys
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{group_families, user_kv_keys};
+    use crate::auth::throttle;
+    use crate::data::refresh::SavedRefreshToken;
+    use crate::utility::usp_hex;
+
+    fn token(id: i32, family_id: &str, iat: i32, exp: i32, successor_id: Option<i32>) -> SavedRefreshToken {
+        SavedRefreshToken {
+            id,
+            family_id: family_id.to_string(),
+            user_usph: "u".to_string(),
+            access_value: "a".to_string(),
+            id_token_value: "i".to_string(),
+            iat,
+            exp,
+            nonce: "n".to_string(),
+            client_id: "c".to_string(),
+            family_iat: 100,
+            successor_id,
+            rotated_at: successor_id.map(|_| iat + 10)
+        }
+    }
+
+    #[test]
+    fn test_group_families() {
+        let tokens = vec![
+            token(1, "f1", 100, 200, Some(2)),
+            token(2, "f1", 110, 210, None),
+            token(3, "f2", 100, 150, None),
+        ];
+        let (sessions, families) = group_families(tokens, 160);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].family_id, "f1");
+        assert_eq!(sessions[0].refreshed_at, 110);
+        assert_eq!(families.len(), 2);
+        assert_eq!(families.iter().find(|f| f.family_id == "f1").unwrap().tokens.len(), 2);
+    }
+
+    #[test]
+    fn test_user_kv_keys() {
+        // "user" is also a segment of every user's throttle key
+        let keys = user_kv_keys(&usp_hex("user"), 1_000_000);
+        let other = &throttle::user_kv_keys(&usp_hex("alice"))[0];
+        assert!(!keys.contains(other));
+        assert!(keys.contains(&throttle::user_kv_keys(&usp_hex("user"))[0]));
+        assert!(keys.iter().all(|key| key.contains(&usp_hex("user")) && !key.contains('*')));
+    }
+}
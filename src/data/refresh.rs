@@ -1,72 +1,167 @@
-use sea_query::{Value, Values};
-use crate::data::db::{Database, Row};
+use sea_query::{Expr, Iden, Order, Query, Value};
+use serde::Serialize;
+use crate::data::db::{Database, delete_from, Row, select_from, Tx, tx_delete, tx_insert_return_id, tx_retrieve_one, tx_retrieve_one_for_update, tx_update};
 use crate::data::source::Source;
 use crate::error::Error;
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(Iden, Clone, Copy)]
+pub enum Refreshtokens {
+    Table,
+    Id,
+    FamilyId,
+    UserUsph,
+    ClientId,
+    Exp,
+    FamilyIat,
+    SuccessorId,
+    RotatedAt
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum RefreshReuseEvents {
+    Table,
+    Id,
+    UserUsph
+}
+
+#[derive(sqlx::FromRow, Row, Debug, Clone)]
 pub struct SavedRefreshToken {
     pub id: i32,
     pub family_id: String,
+    pub user_usph: String,
     pub access_value: String,
     pub id_token_value: String,
     pub iat: i32,
     pub exp: i32,
     pub nonce: String,
+    /// Empty for families created before tokens were bound to a client
+    pub client_id: String,
+    /// Creation time of the family, for the maximum family age
+    pub family_iat: i32,
+    /// Set once the token is rotated
+    pub successor_id: Option<i32>,
+    pub rotated_at: Option<i32>,
 }
 
-impl<'a> Row for &'a SavedRefreshToken {
-    fn keys(&self, include_id: bool) -> &str {
-        if include_id {
-            "id, family_id, access_value, id_token_value, iat, exp, nonce"
-        } else {
-            "family_id, access_value, id_token_value, iat, exp, nonce"
-        }
-    }
-
-    fn vals(&self, include_id: bool) -> &str {
-        if include_id {
-            "$1, $2, $3, $4, $5, $6, $7"
-        } else {
-            "$1, $2, $3, $4, $5, $6"
-        }
-    }
-
-    fn set(&self) -> &str {
-        "id = $1, family_id = $2, access_value = $3, id_token_value = $4, iat =$5, exp = $6, nonce = $7"
-    }
-
-    fn values(&self, include_id: bool) -> Values {
-        let mut val_vec = vec![
-            Value::from(self.family_id.clone()),
-            Value::from(self.access_value.clone()),
-            Value::from(self.id_token_value.clone()),
-            Value::from(self.iat),
-            Value::from(self.exp),
-            Value::from(self.nonce.clone()),
-        ];
-
-        if include_id {
-            val_vec.insert(0, Value::from(self.id));
-        }
-
-        Values(val_vec)
-    }
+#[derive(sqlx::FromRow, Row, Serialize, Debug)]
+pub struct RefreshReuseEvent {
+    pub id: i32,
+    pub family_id: String,
+    pub user_usph: String,
+    pub client_id: String,
+    pub refresh_id: i32,
+    pub occurred_at: i32,
+    pub family_revoked: bool
 }
 
 pub async fn refresh_save(dsrc: &Source, row: &SavedRefreshToken) -> Result<i32, Error> {
-    dsrc.db.insert_return_id("refreshtokens", row).await
+    dsrc.db.insert_return_id(Refreshtokens::Table, row).await
+}
+
+/// Locks the token until the transaction ends, so concurrent refreshes with the same token are handled
+/// one after the other
+pub async fn lock_refresh_by_id(tx: &mut Tx, id: i32) -> Result<Option<SavedRefreshToken>, Error> {
+    let query = select_from(Refreshtokens::Table).and_where(Expr::col(Refreshtokens::Id).eq(id)).to_owned();
+    tx_retrieve_one_for_update(tx, &query).await
+}
+
+pub async fn get_refresh_by_id_tx(tx: &mut Tx, id: i32) -> Result<Option<SavedRefreshToken>, Error> {
+    let query = select_from(Refreshtokens::Table).and_where(Expr::col(Refreshtokens::Id).eq(id)).to_owned();
+    tx_retrieve_one(tx, &query).await
 }
 
-pub async fn get_refresh_by_id(dsrc: &Source, id: i32) -> Result<SavedRefreshToken, Error> {
-    dsrc.db.retrieve_by_id("refreshtokens", id).await?.ok_or(Error::NoRow)
+/// Saves the successor and marks the old token as rotated, which must have been locked in the same
+/// transaction
+pub async fn rotate_refresh(tx: &mut Tx, old_id: i32, successor: &SavedRefreshToken, rotated_at: i32) -> Result<i32, Error> {
+    let successor_id = tx_insert_return_id(tx, Refreshtokens::Table, successor).await?;
+    let query = Query::update()
+        .table(Refreshtokens::Table)
+        .values(vec![
+            (Refreshtokens::SuccessorId, Value::from(successor_id)),
+            (Refreshtokens::RotatedAt, Value::from(rotated_at)),
+        ])
+        .and_where(Expr::col(Refreshtokens::Id).eq(old_id))
+        .to_owned();
+    tx_update(tx, &query).await?;
+    Ok(successor_id)
 }
 
-pub async fn refresh_transaction(dsrc: &Source, id_delete: i32, new_refresh: &SavedRefreshToken) -> Result<i32, Error> {
-    // CURRENTLY DOES NOT FAIL IF IT DOES NOT EXIST
-    // TODO add check in query delete if it did delete
-    dsrc.db.delete_insert_return_id_transaction("refreshtokens", id_delete, new_refresh).await
+pub async fn delete_family_tx(tx: &mut Tx, family_id: &str) -> Result<(), Error> {
+    let query = delete_from(Refreshtokens::Table).and_where(Expr::col(Refreshtokens::FamilyId).eq(family_id)).to_owned();
+    tx_delete(tx, &query).await.map(|_| ())
+}
+
+pub async fn save_reuse_event(tx: &mut Tx, event: &RefreshReuseEvent) -> Result<(), Error> {
+    tx_insert_return_id(tx, RefreshReuseEvents::Table, event).await.map(|_| ())
 }
 
 pub async fn delete_family(dsrc: &Source, family_id: &str) -> Result<(), Error> {
-    dsrc.db.delete_by_column::<>("refreshtokens", "family_id", family_id).await
-}
\ No newline at end of file
+    let query = delete_from(Refreshtokens::Table).and_where(Expr::col(Refreshtokens::FamilyId).eq(family_id)).to_owned();
+    dsrc.db.delete(&query).await.map(|_| ())
+}
+
+pub async fn delete_user_families(dsrc: &Source, user_usph: &str) -> Result<(), Error> {
+    let query = delete_from(Refreshtokens::Table).and_where(Expr::col(Refreshtokens::UserUsph).eq(user_usph)).to_owned();
+    dsrc.db.delete(&query).await.map(|_| ())
+}
+
+/// The current token of each family of the user that has not expired, newest login first
+pub async fn list_user_sessions(dsrc: &Source, user_usph: &str, now: i32) -> Result<Vec<SavedRefreshToken>, Error> {
+    let query = select_from(Refreshtokens::Table)
+        .and_where(Expr::col(Refreshtokens::UserUsph).eq(user_usph))
+        .and_where(Expr::col(Refreshtokens::SuccessorId).is_null())
+        .and_where(Expr::col(Refreshtokens::Exp).gte(now))
+        .order_by(Refreshtokens::FamilyIat, Order::Desc)
+        .to_owned();
+    dsrc.db.retrieve_all::<SavedRefreshToken>(&query).await
+}
+
+/// All tokens of the user, including rotated and expired ones that were not collected yet
+pub async fn list_user_refresh(dsrc: &Source, user_usph: &str) -> Result<Vec<SavedRefreshToken>, Error> {
+    let query = select_from(Refreshtokens::Table)
+        .and_where(Expr::col(Refreshtokens::UserUsph).eq(user_usph))
+        .order_by(Refreshtokens::Id, Order::Asc)
+        .to_owned();
+    dsrc.db.retrieve_all::<SavedRefreshToken>(&query).await
+}
+
+pub async fn list_user_reuse_events(dsrc: &Source, user_usph: &str) -> Result<Vec<RefreshReuseEvent>, Error> {
+    let query = select_from(RefreshReuseEvents::Table)
+        .and_where(Expr::col(RefreshReuseEvents::UserUsph).eq(user_usph))
+        .order_by(RefreshReuseEvents::Id, Order::Asc)
+        .to_owned();
+    dsrc.db.retrieve_all::<RefreshReuseEvent>(&query).await
+}
+
+/// Only deletes the family if it belongs to the user, returning whether it did
+pub async fn delete_user_family(dsrc: &Source, user_usph: &str, family_id: &str) -> Result<bool, Error> {
+    let query = delete_from(Refreshtokens::Table)
+        .and_where(Expr::col(Refreshtokens::UserUsph).eq(user_usph))
+        .and_where(Expr::col(Refreshtokens::FamilyId).eq(family_id))
+        .to_owned();
+    Ok(dsrc.db.delete(&query).await? > 0)
+}
+
+/// Ends every session the user has with the client
+pub async fn delete_user_client_families(dsrc: &Source, user_usph: &str, client_id: &str) -> Result<(), Error> {
+    let query = delete_from(Refreshtokens::Table)
+        .and_where(Expr::col(Refreshtokens::UserUsph).eq(user_usph))
+        .and_where(Expr::col(Refreshtokens::ClientId).eq(client_id))
+        .to_owned();
+    dsrc.db.delete(&query).await.map(|_| ())
+}
+
+/// Deletes at most `batch_size` tokens that expired before `cutoff`, returning how many were deleted. Small
+/// batches keep each statement short, so refreshes are not blocked for long.
+pub async fn delete_expired_batch(dsrc: &Source, cutoff: i32, batch_size: u64) -> Result<u64, Error> {
+    let expired = Query::select()
+        .column(Refreshtokens::Id)
+        .from(Refreshtokens::Table)
+        .and_where(Expr::col(Refreshtokens::Exp).lt(cutoff))
+        .limit(batch_size)
+        .to_owned();
+    let query = delete_from(Refreshtokens::Table)
+        .and_where(Expr::col(Refreshtokens::Id).in_subquery(expired))
+        .to_owned();
+    dsrc.db.delete(&query).await
+}
@@ -1,25 +1,54 @@
 use std::time::Duration;
 use sqlx::postgres::PgPoolOptions;
+use crate::config::ConnectRetryConfig;
+use crate::data::audit::AuditFile;
 use crate::data::db::{PSQL};
 use crate::data::kv::{Redis};
 use crate::error::Error;
 
 pub struct Source {
     pub db: PSQL,
-    pub kv: Redis
+    pub kv: Redis,
+    /// Also append audit events to this file, see `Source::with_audit_file`
+    pub audit_file: Option<AuditFile>
 }
 
 impl Source {
     pub async fn new(db_uri: &str, kv_uri: &str) -> Result<Self, Error> {
         let db_pool = PgPoolOptions::new().connect_timeout(Duration::from_secs(1))
             .connect(db_uri).await?;
-        let client = redis::Client::open(kv_uri).unwrap();
-        let kv_conn_manager = redis::aio::ConnectionManager::new(client).await.unwrap();
+        let client = redis::Client::open(kv_uri)?;
+        let kv_conn_manager = redis::aio::ConnectionManager::new(client).await?;
         let psql = PSQL { pool: db_pool };
         let redis = Redis { conn_manager: kv_conn_manager };
         Ok(Self {
             db: psql,
-            kv: redis
+            kv: redis,
+            audit_file: None
         })
     }
+
+    /// Retries `Source::new` with exponential backoff, returning the last error once all attempts failed
+    pub async fn connect_with_retry(db_uri: &str, kv_uri: &str, retry: &ConnectRetryConfig) -> Result<Self, Error> {
+        let mut backoff = Duration::from_millis(retry.initial_backoff_ms);
+        let mut attempt = 1;
+        loop {
+            match Self::new(db_uri, kv_uri).await {
+                Ok(source) => return Ok(source),
+                Err(e) if attempt < retry.attempts => {
+                    tracing::warn!("connecting to backends failed (attempt {} of {}), retrying in {:?}: {}",
+                        attempt, retry.attempts, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_millis(retry.max_backoff_ms));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    pub fn with_audit_file(mut self, path: Option<&str>) -> Self {
+        self.audit_file = path.map(AuditFile::new);
+        self
+    }
 }
\ No newline at end of file
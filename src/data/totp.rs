@@ -0,0 +1,125 @@
+use sea_query::{Expr, Iden, Query, Value};
+use crate::auth::auth::{symmetric_crypt, symmetric_decrypt};
+use crate::data::db::{Database, delete_from, Row, select_from, Tx, tx_retrieve_all_for_update, tx_update};
+use crate::data::source::Source;
+use crate::error::Error;
+use crate::utility::{dec_b64url, enc_b64url};
+
+#[derive(Iden, Clone, Copy)]
+pub enum Totp {
+    Table,
+    Id,
+    UserUsph,
+    Secret
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum RecoveryCodes {
+    Table,
+    UserUsph,
+    CodeHash
+}
+
+/// The secret is stored encrypted with the symmetric key
+#[derive(sqlx::FromRow, Row, Debug)]
+pub struct TotpSecret {
+    pub id: i32,
+    pub user_usph: String,
+    pub secret: String
+}
+
+#[derive(sqlx::FromRow, Row, Debug)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_usph: String,
+    pub code_hash: String
+}
+
+pub async fn get_totp_by_usph(dsrc: &Source, user_usph: &str) -> Result<Option<TotpSecret>, Error> {
+    let query = select_from(Totp::Table).and_where(Expr::col(Totp::UserUsph).eq(user_usph)).to_owned();
+    dsrc.db.retrieve_one::<TotpSecret>(&query).await
+}
+
+/// Replaces the existing secret of the user, if there is one
+pub async fn save_totp(dsrc: &Source, user_usph: &str, secret: String) -> Result<(), Error> {
+    match get_totp_by_usph(dsrc, user_usph).await? {
+        Some(existing) => {
+            let totp = TotpSecret { secret, ..existing };
+            dsrc.db.upsert_by_id(Totp::Table, &totp).await
+        }
+        None => {
+            let totp = TotpSecret { id: 0, user_usph: user_usph.to_owned(), secret };
+            dsrc.db.insert_return_id(Totp::Table, &totp).await.map(|_| ())
+        }
+    }
+}
+
+/// Replaces all existing recovery codes of the user
+pub async fn save_recovery_codes(dsrc: &Source, user_usph: &str, code_hashes: Vec<String>) -> Result<(), Error> {
+    delete_recovery_codes(dsrc, user_usph).await?;
+    for code_hash in code_hashes {
+        let code = RecoveryCode { id: 0, user_usph: user_usph.to_owned(), code_hash };
+        dsrc.db.insert_return_id(RecoveryCodes::Table, &code).await?;
+    }
+    Ok(())
+}
+
+/// Returns whether a matching code existed, deleting it so it can only be used once
+pub async fn use_recovery_code(dsrc: &Source, user_usph: &str, code_hash: &str) -> Result<bool, Error> {
+    let query = select_from(RecoveryCodes::Table)
+        .and_where(Expr::col(RecoveryCodes::CodeHash).eq(code_hash))
+        .and_where(Expr::col(RecoveryCodes::UserUsph).eq(user_usph))
+        .to_owned();
+    let code = match dsrc.db.retrieve_one::<RecoveryCode>(&query).await? {
+        Some(code) => code,
+        None => return Ok(false)
+    };
+    // When used concurrently, only one of the deletes succeeds
+    match dsrc.db.delete_by_id_required(RecoveryCodes::Table, code.id).await {
+        Ok(()) => Ok(true),
+        Err(Error::NoRow) => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+async fn delete_recovery_codes(dsrc: &Source, user_usph: &str) -> Result<(), Error> {
+    let query = delete_from(RecoveryCodes::Table).and_where(Expr::col(RecoveryCodes::UserUsph).eq(user_usph)).to_owned();
+    dsrc.db.delete(&query).await.map(|_| ())
+}
+
+fn reencrypt_secret(old_key: &[u8], new_key: &[u8], secret: &str) -> Result<String, Error> {
+    let plain = symmetric_decrypt(old_key, dec_b64url(secret)?)?;
+    Ok(enc_b64url(symmetric_crypt(new_key, plain)?))
+}
+
+/// Re-encrypts all secrets with the new symmetric key, when it is rotated in the same transaction.
+/// Returns the number of secrets.
+pub async fn tx_reencrypt_totp_secrets(tx: &mut Tx, old_key: &[u8], new_key: &[u8]) -> Result<usize, Error> {
+    let secrets: Vec<TotpSecret> = tx_retrieve_all_for_update(tx, &select_from(Totp::Table)).await?;
+    for totp in &secrets {
+        let query = Query::update()
+            .table(Totp::Table)
+            .values(vec![(Totp::Secret, Value::from(reencrypt_secret(old_key, new_key, &totp.secret)?))])
+            .and_where(Expr::col(Totp::Id).eq(totp.id))
+            .to_owned();
+        tx_update(tx, &query).await?;
+    }
+    Ok(secrets.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::keyutil::new_symmetric_keypair;
+    use super::*;
+
+    #[test]
+    fn test_reencrypt_secret() {
+        let old_key = dec_b64url(new_symmetric_keypair().private).unwrap();
+        let new_key = dec_b64url(new_symmetric_keypair().private).unwrap();
+        let secret = enc_b64url(symmetric_crypt(&old_key, b"totp secret".to_vec()).unwrap());
+
+        let reencrypted = reencrypt_secret(&old_key, &new_key, &secret).unwrap();
+        assert_eq!(symmetric_decrypt(&new_key, dec_b64url(&reencrypted).unwrap()).unwrap(), b"totp secret");
+        assert!(symmetric_decrypt(&old_key, dec_b64url(&reencrypted).unwrap()).is_err());
+    }
+}
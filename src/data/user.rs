@@ -1,64 +1,126 @@
-use sea_query::{Value, Values};
-use crate::data::db::{Database, Row};
+use sea_query::{DeleteStatement, Expr, Iden, Order};
+use crate::data::consent::Consents;
+use crate::data::db::{Database, delete_from, Row, select_from, tx_delete};
+use crate::data::refresh::{delete_user_families, RefreshReuseEvents, Refreshtokens};
+use crate::data::totp::{RecoveryCodes, Totp};
+use crate::data::webauthn::WebauthnCredentials;
 use crate::data::source::Source;
 use crate::error::Error;
+use crate::utility::usp_hex;
 
-#[derive(sqlx::FromRow, Debug)]
+#[derive(Iden, Clone, Copy)]
+pub enum Users {
+    Table,
+    Id,
+    UspHex,
+    PasswordFile,
+    Email,
+    EmailVerified,
+    Admin,
+    Disabled
+}
+
+#[derive(sqlx::FromRow, Row, Debug)]
 pub struct User {
     pub usp_hex: String,
     pub id: i32,
-    pub password_file: String
+    pub password_file: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// Display name, chosen by the user
+    pub name: Option<String>,
+    pub admin: bool,
+    pub disabled: bool
 }
 
-impl<'a> Row for &'a User {
-    fn keys(&self, include_id: bool) -> &str {
-        if include_id {
-            "id, usp_hex, password_file"
-        } else {
-            "usp_hex, password_file"
-        }
-    }
+pub async fn get_user_by_id(dsrc: &Source, id: i32) -> Result<Option<User>, Error> {
+    dsrc.db.retrieve_by_id::<User, _>(Users::Table, id).await
+}
 
-    fn vals(&self, include_id: bool) -> &str {
-        if include_id {
-            "$1, $2, $3"
-        } else {
-            "$1, $2"
-        }
-    }
+pub async fn get_user_by_usph(dsrc: &Source, usp_hex: &str) -> Result<Option<User>, Error> {
+    let query = select_from(Users::Table).and_where(Expr::col(Users::UspHex).eq(usp_hex)).to_owned();
+    dsrc.db.retrieve_one::<User>(&query).await
+}
 
-    fn set(&self) -> &str {
-        "id = $1, usp_hex = $2, password_file = $3"
-    }
+/// Only verified addresses are unique, unverified ones can be set on any number of users
+pub async fn get_user_by_verified_email(dsrc: &Source, email: &str) -> Result<Option<User>, Error> {
+    let query = select_from(Users::Table)
+        .and_where(Expr::col(Users::Email).eq(email))
+        .and_where(Expr::col(Users::EmailVerified).eq(true))
+        .to_owned();
+    dsrc.db.retrieve_one::<User>(&query).await
+}
 
-    fn values(&self, include_id: bool) -> Values {
-        let mut val_vec = vec![
-            Value::from(self.usp_hex.clone()),
-            Value::from(self.password_file.clone()),
-        ];
+/// Relies on the unique index on verified addresses in `users.email`, as it is the only unique column
+/// that changes
+pub async fn upsert_user_row(dsrc: &Source, row: &User) -> Result<(), Error> {
+    dsrc.db.upsert_by_id(Users::Table, row).await.map_err(|e| match e {
+        Error::UniqueViolation => Error::EmailUnavailable,
+        e => e
+    })
+}
 
-        if include_id {
-            val_vec.insert(0, Value::from(self.id));
-        }
+/// Returns `Error::UserExists` if the usp_hex is taken, relying on the `users_usp_hex_idx` unique index
+/// from the initial migration. Registration checks for an existing user first, so there this only
+/// happens when two registrations race.
+pub async fn new_user_return_id(dsrc: &Source, row: &User) -> Result<i32, Error> {
+    dsrc.db.insert_return_id(Users::Table, row).await.map_err(|e| match e {
+        Error::UniqueViolation => Error::UserExists,
+        e => e
+    })
+}
 
-        Values(val_vec)
+/// Lists users ordered by id, optionally only those whose username starts with `search`
+pub async fn list_users(dsrc: &Source, search: Option<&str>, limit: u64, offset: u64) -> Result<Vec<User>, Error> {
+    let mut query = select_from(Users::Table);
+    // The fake record is not a user
+    query.and_where(Expr::col(Users::Id).ne(0))
+        .order_by(Users::Id, Order::Asc)
+        .limit(limit)
+        .offset(offset);
+    if let Some(search) = search {
+        // usp_hex only leaves "_" of the LIKE wildcards, so that is the only one to escape
+        let pattern = format!("{}%", usp_hex(search).replace('_', "\\_"));
+        query.and_where(Expr::col(Users::UspHex).like(&pattern));
     }
+    dsrc.db.retrieve_all::<User>(&query).await
 }
 
-pub async fn get_user_by_id(dsrc: &Source, id: i32) -> Result<Option<User>, Error> {
-    dsrc.db.retrieve_by_id::<User>("users", id).await
-}
+/// Disabling also revokes all refresh token families of the user
+pub async fn set_disabled(dsrc: &Source, mut user: User, disabled: bool) -> Result<User, Error> {
+    user.disabled = disabled;
+    upsert_user_row(dsrc, &user).await?;
+    if disabled {
+        delete_user_families(dsrc, &user.usp_hex).await?;
+    }
 
-pub async fn get_user_by_usph(dsrc: &Source, usp_hex: &str) -> Result<Option<User>, Error> {
-    let val = Values(vec![Value::from(usp_hex)]);
-    dsrc.db.retrieve_by_unique::<User>("users", "usp_hex", val).await
+    Ok(user)
 }
 
-// Uncomment when used
-// pub async fn upsert_user_row(dsrc: &Source, row: &User) -> Result<(), Error> {
-//     dsrc.db.upsert_by_id("users", row).await
-// }
+/// Every table with rows tied to the usp_hex, except audit events. The users row is last.
+pub(crate) fn user_deletes(user_usph: &str) -> Vec<DeleteStatement> {
+    vec![
+        delete_from(Refreshtokens::Table).and_where(Expr::col(Refreshtokens::UserUsph).eq(user_usph)).to_owned(),
+        delete_from(RefreshReuseEvents::Table).and_where(Expr::col(RefreshReuseEvents::UserUsph).eq(user_usph)).to_owned(),
+        delete_from(RecoveryCodes::Table).and_where(Expr::col(RecoveryCodes::UserUsph).eq(user_usph)).to_owned(),
+        delete_from(Totp::Table).and_where(Expr::col(Totp::UserUsph).eq(user_usph)).to_owned(),
+        delete_from(WebauthnCredentials::Table).and_where(Expr::col(WebauthnCredentials::UserUsph).eq(user_usph)).to_owned(),
+        delete_from(Consents::Table).and_where(Expr::col(Consents::UserUsph).eq(user_usph)).to_owned(),
+        delete_from(Users::Table).and_where(Expr::col(Users::UspHex).eq(user_usph)).to_owned(),
+    ]
+}
 
-pub async fn new_user_return_id(dsrc: &Source, row: &User) -> Result<i32, Error> {
-    dsrc.db.insert_return_id("users", row).await
+/// Deletes the user along with their refresh tokens, second factors and consents in one transaction.
+/// Returns `Error::NoRow` if the user was already deleted.
+pub async fn delete_user(dsrc: &Source, user: &User) -> Result<(), Error> {
+    let mut tx = dsrc.db.begin().await?;
+    let mut deleted = 0;
+    for query in user_deletes(&user.usp_hex) {
+        deleted = tx_delete(&mut tx, &query).await?;
+    }
+    if deleted == 0 {
+        return Err(Error::NoRow)
+    }
+    tx.commit().await?;
+    Ok(())
 }
\ No newline at end of file
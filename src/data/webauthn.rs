@@ -0,0 +1,59 @@
+use sea_query::{Expr, Iden};
+use webauthn_rs::prelude::Passkey;
+use crate::data::db::{Database, Row, select_from};
+use crate::data::source::Source;
+use crate::error::Error;
+use crate::utility::enc_b64url;
+
+#[derive(Iden, Clone, Copy)]
+pub enum WebauthnCredentials {
+    Table,
+    UserUsph,
+    CredentialId
+}
+
+/// The passkey is stored as JSON, with its credential id separately so it can be looked up
+#[derive(sqlx::FromRow, Row, Debug)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_usph: String,
+    pub credential_id: String,
+    pub passkey: String
+}
+
+fn credential_id(passkey: &Passkey) -> String {
+    enc_b64url(&passkey.cred_id().0)
+}
+
+pub async fn get_passkeys_by_usph(dsrc: &Source, user_usph: &str) -> Result<Vec<Passkey>, Error> {
+    let query = select_from(WebauthnCredentials::Table)
+        .and_where(Expr::col(WebauthnCredentials::UserUsph).eq(user_usph))
+        .to_owned();
+    let credentials = dsrc.db.retrieve_all::<WebauthnCredential>(&query).await?;
+    credentials.iter().map(|c| Ok(serde_json::from_str(&c.passkey)?)).collect()
+}
+
+pub async fn save_passkey(dsrc: &Source, user_usph: &str, passkey: &Passkey) -> Result<(), Error> {
+    let credential = WebauthnCredential {
+        id: 0,
+        user_usph: user_usph.to_owned(),
+        credential_id: credential_id(passkey),
+        passkey: serde_json::to_string(passkey)?
+    };
+    dsrc.db.insert_return_id(WebauthnCredentials::Table, &credential).await.map(|_| ())
+}
+
+/// Stores the passkey after authentication, as its signature counter may have changed
+pub async fn update_passkey(dsrc: &Source, user_usph: &str, passkey: &Passkey) -> Result<(), Error> {
+    let query = select_from(WebauthnCredentials::Table)
+        .and_where(Expr::col(WebauthnCredentials::CredentialId).eq(credential_id(passkey)))
+        .and_where(Expr::col(WebauthnCredentials::UserUsph).eq(user_usph))
+        .to_owned();
+    let existing = dsrc.db.retrieve_one::<WebauthnCredential>(&query).await?
+        .ok_or(Error::NoRow)?;
+    let credential = WebauthnCredential {
+        passkey: serde_json::to_string(passkey)?,
+        ..existing
+    };
+    dsrc.db.upsert_by_id(WebauthnCredentials::Table, &credential).await
+}
@@ -6,6 +6,60 @@ pub enum Error {
     #[error("no row")]
     NoRow,
 
+    #[error("unique constraint violated")]
+    UniqueViolation,
+
+    #[error("user already exists")]
+    UserExists,
+
+    #[error("email address already in use")]
+    EmailUnavailable,
+
+    #[error("invalid or expired email token")]
+    InvalidEmailToken,
+
+    #[error("incorrect username or password")]
+    IncorrectCredentials,
+
+    #[error("too many failed login attempts, retry after {0} seconds")]
+    LoginThrottled(u64),
+
+    #[error("rate limited, retry after {0} seconds")]
+    RateLimited(u64),
+
+    #[error("missing or invalid access token")]
+    Unauthorized,
+
+    #[error("forbidden")]
+    Forbidden,
+
+    #[error("log in again to continue")]
+    LoginRequired,
+
+    #[error("user not found")]
+    UserNotFound,
+
+    #[error("consent not found")]
+    ConsentNotFound,
+
+    #[error("session not found")]
+    SessionNotFound,
+
+    #[error("erasure unavailable: {0}")]
+    ErasureUnavailable(String),
+
+    #[error("client already exists")]
+    ClientExists,
+
+    #[error("unknown client or redirect_uri")]
+    UnknownClient,
+
+    #[error("incorrect second factor")]
+    IncorrectSecondFactor,
+
+    #[error("no webauthn credentials")]
+    NoWebauthnCredentials,
+
     #[error("required does not exist")]
     RequiredNotExists,
 
@@ -36,6 +90,9 @@ pub enum Error {
     #[error("db error: {0}")]
     DbError(#[from] sqlx::Error),
 
+    #[error("migrate error: {0}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+
     #[error("kv error: {0}")]
     KvError(#[from] redis::RedisError),
 
@@ -45,6 +102,30 @@ pub enum Error {
     #[error("decode error base64: {0}")]
     DecodeError(#[from] base64::DecodeError),
 
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("config error: {0}")]
+    ConfigError(#[from] toml::de::Error),
+
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("mail error: {0}")]
+    MailError(String),
+
+    #[error("metrics error: {0}")]
+    MetricsError(String),
+
+    #[error("tls error: {0}")]
+    TlsError(String),
+
+    #[error("server error: {0}")]
+    ServerError(#[from] hyper::Error),
+
+    #[error("webauthn error: {0}")]
+    WebauthnError(#[from] webauthn_rs::prelude::WebauthnError),
+
     #[error("crypt error")]
     BadCryptInput
 }
@@ -60,6 +141,12 @@ pub enum BadFlow {
     #[error("expired code")]
     ExpiredCode,
 
+    #[error("expired enrollment")]
+    ExpiredEnrollment,
+
+    #[error("expired mfa_id")]
+    ExpiredMfaId,
+
     #[error("bad challenge")]
     BadChallenge
 }
\ No newline at end of file
@@ -6,5 +6,8 @@ mod utility;
 mod auth;
 mod server;
 mod config;
+mod mail;
+mod cli;
 
 pub use crate::server::run_server;
+pub use crate::cli::run_admin;
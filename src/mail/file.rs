@@ -0,0 +1,35 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use crate::error::Error;
+use crate::mail::{Mail, Mailer};
+
+/// Appends each mail as a JSON line to a file instead of sending it
+pub struct FileMailer {
+    path: PathBuf,
+    lock: Mutex<()>
+}
+
+impl FileMailer {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            lock: Mutex::new(())
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&mail)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Mutex;
+use async_trait::async_trait;
+use crate::error::Error;
+use crate::mail::{Mail, Mailer};
+
+/// Keeps sent mail in memory, for tests and local development
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Mail>>
+}
+
+impl MemoryMailer {
+    pub fn new() -> Self {
+        Self {
+            sent: Mutex::new(vec![])
+        }
+    }
+
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Default for MemoryMailer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
+
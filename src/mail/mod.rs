@@ -0,0 +1,42 @@
+mod smtp;
+mod memory;
+mod file;
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde::Serialize;
+use crate::config::MailConfig;
+use crate::error::Error;
+
+pub use smtp::SmtpMailer;
+pub use memory::MemoryMailer;
+pub use file::FileMailer;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+#[async_trait]
+pub trait Mailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error>;
+}
+
+pub type DynMailer = Arc<dyn Mailer + Send + Sync>;
+
+pub fn mailer_from_config(config: &MailConfig) -> Result<DynMailer, Error> {
+    if let Some(smtp) = &config.smtp {
+        Ok(Arc::new(SmtpMailer::new(smtp, &config.from)?))
+    } else if let Some(path) = &config.file {
+        Ok(Arc::new(FileMailer::new(path)))
+    } else {
+        tracing::warn!("no smtp or file mailer configured, mail will only be kept in memory");
+        Ok(Arc::new(MemoryMailer::new()))
+    }
+}
+
+pub fn valid_address(address: &str) -> bool {
+    address.parse::<lettre::Address>().is_ok()
+}
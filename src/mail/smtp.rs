@@ -0,0 +1,45 @@
+use async_trait::async_trait;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use crate::config::SmtpConfig;
+use crate::error::Error;
+use crate::mail::{Mail, Mailer};
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox
+}
+
+impl SmtpMailer {
+    pub fn new(config: &SmtpConfig, from: &str) -> Result<Self, Error> {
+        let from = from.parse::<Mailbox>().map_err(|e| Error::MailError(e.to_string()))?;
+        let credentials = Credentials::new(config.username.clone(), config.password.clone());
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+            .map_err(|e| Error::MailError(e.to_string()))?
+            .port(config.port)
+            .credentials(credentials)
+            .build();
+
+        Ok(Self {
+            transport,
+            from
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<(), Error> {
+        let to = mail.to.parse::<Mailbox>().map_err(|e| Error::MailError(e.to_string()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(mail.subject)
+            .body(mail.body)
+            .map_err(|e| Error::MailError(e.to_string()))?;
+
+        self.transport.send(message).await.map_err(|e| Error::MailError(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
 #[tokio::main]
 async fn main() {
-    tiauth2::run_server().await;
+    if let Err(e) = tiauth2::run_server().await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use axum::extract::{Extension, Path};
+use axum::Json;
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
+use crate::data::consent::{Consent, list_consents, revoke_consent};
+use crate::data::refresh::{delete_user_client_families, delete_user_family, list_user_sessions, SavedRefreshToken};
+use crate::data::source::Source;
+use crate::data::user;
+use crate::data::user::{upsert_user_row, User};
+use crate::error::Error;
+use crate::server::bearer::AccountAuthenticated;
+use crate::server::ip::ClientIp;
+use crate::server::models::{AccountConsent, AccountProfile, AccountSession, UpdateProfile};
+use crate::utility::{usp_dehex, utc_timestamp};
+
+const MAX_NAME_LENGTH: usize = 256;
+/// Deleting the account requires a login at most this long ago
+const DELETE_LOGIN_MAX_AGE: u64 = 5 * 60;
+
+impl From<User> for AccountProfile {
+    fn from(user: User) -> Self {
+        Self {
+            username: usp_dehex(&user.usp_hex),
+            sub: user.usp_hex,
+            name: user.name,
+            email: user.email,
+            email_verified: user.email_verified
+        }
+    }
+}
+
+impl From<SavedRefreshToken> for AccountSession {
+    fn from(token: SavedRefreshToken) -> Self {
+        Self {
+            family_id: token.family_id,
+            client_id: token.client_id,
+            issued_at: token.family_iat,
+            refreshed_at: token.iat,
+            expires_at: token.exp
+        }
+    }
+}
+
+impl From<Consent> for AccountConsent {
+    fn from(consent: Consent) -> Self {
+        Self {
+            client_id: consent.client_id,
+            scope: consent.scope,
+            granted_at: consent.granted_at
+        }
+    }
+}
+
+async fn account_user(dsrc: &Source, user_usph: &str) -> Result<User, Error> {
+    user::get_user_by_usph(dsrc, user_usph).await?.ok_or(Error::UserNotFound)
+}
+
+pub async fn account_profile(AccountAuthenticated(claims): AccountAuthenticated, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<AccountProfile>, Error> {
+    Ok(Json(account_user(&dsrc, &claims.sub).await?.into()))
+}
+
+pub async fn update_profile(AccountAuthenticated(claims): AccountAuthenticated, Json(update): Json<UpdateProfile>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<Json<AccountProfile>, Error> {
+    let mut user = account_user(&dsrc, &claims.sub).await?;
+    if let Some(name) = update.name {
+        let name = name.trim();
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(Error::IncorrectField(format!("name is longer than {} characters", MAX_NAME_LENGTH)))
+        }
+        user.name = Some(name.to_owned()).filter(|n| !n.is_empty());
+    }
+    upsert_user_row(&dsrc, &user).await?;
+    record(&dsrc, NewAuditEvent::new(AuditKind::ProfileUpdated).user(&claims.sub).ip(&ip)).await;
+
+    Ok(Json(user.into()))
+}
+
+/// Deletes the account with everything tied to it, the username can be registered again afterwards. A
+/// stolen token is not enough, the user must have logged in just before.
+pub async fn delete_account(AccountAuthenticated(claims): AccountAuthenticated, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<(), Error> {
+    if !claims.recent_login(DELETE_LOGIN_MAX_AGE, utc_timestamp()) {
+        return Err(Error::LoginRequired)
+    }
+    let user = account_user(&dsrc, &claims.sub).await?;
+    user::delete_user(&dsrc, &user).await?;
+    record(&dsrc, NewAuditEvent::new(AuditKind::AccountDeleted).user(&claims.sub).ip(&ip)).await;
+
+    Ok(())
+}
+
+/// Each session is a refresh token family, started by a login at a client
+pub async fn account_sessions(AccountAuthenticated(claims): AccountAuthenticated, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<Vec<AccountSession>>, Error> {
+    let sessions = list_user_sessions(&dsrc, &claims.sub, utc_timestamp() as i32).await?;
+    Ok(Json(sessions.into_iter().map(AccountSession::from).collect()))
+}
+
+/// Access tokens issued in the session remain valid until they expire
+pub async fn revoke_session(AccountAuthenticated(claims): AccountAuthenticated, Path(family_id): Path<String>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<(), Error> {
+    if !delete_user_family(&dsrc, &claims.sub, &family_id).await? {
+        return Err(Error::SessionNotFound)
+    }
+
+    let event = NewAuditEvent::new(AuditKind::SessionRevoked)
+        .user(&claims.sub)
+        .ip(&ip)
+        .detail("family_id", family_id);
+    record(&dsrc, event).await;
+
+    Ok(())
+}
+
+pub async fn account_consents(AccountAuthenticated(claims): AccountAuthenticated, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<Vec<AccountConsent>>, Error> {
+    let consents = list_consents(&dsrc, &claims.sub).await?;
+    Ok(Json(consents.into_iter().map(AccountConsent::from).collect()))
+}
+
+/// Also ends the sessions with the client, so it cannot keep refreshing tokens it no longer has consent for
+pub async fn revoke_account_consent(AccountAuthenticated(claims): AccountAuthenticated, Path(client_id): Path<String>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<(), Error> {
+    if !revoke_consent(&dsrc, &claims.sub, &client_id).await? {
+        return Err(Error::ConsentNotFound)
+    }
+    delete_user_client_families(&dsrc, &claims.sub, &client_id).await?;
+
+    let event = NewAuditEvent::new(AuditKind::ConsentRevoked)
+        .user(&claims.sub)
+        .client(&client_id)
+        .ip(&ip);
+    record(&dsrc, event).await;
+
+    Ok(())
+}
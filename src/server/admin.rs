@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use axum::extract::{Extension, Path, Query};
+use axum::Json;
+use crate::auth::tokens::AccessToken;
+use crate::data::audit::{AuditKind, list_events, NewAuditEvent, record};
+use crate::data::privacy::{Erasure, erase_user, export_user, UserExport};
+use crate::data::refresh::delete_user_families;
+use crate::data::source::Source;
+use crate::data::user;
+use crate::data::user::User;
+use crate::error::Error;
+use crate::server::bearer::AdminAuthenticated;
+use crate::server::models::{AdminUser, AuditPage, AuditSearch, UserPage, UserSearch};
+use crate::utility::{usp_dehex, usp_hex};
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+const MAX_PAGE_SIZE: u64 = 200;
+
+impl From<User> for AdminUser {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: usp_dehex(&user.usp_hex),
+            usp_hex: user.usp_hex,
+            email: user.email,
+            email_verified: user.email_verified,
+            admin: user.admin,
+            disabled: user.disabled
+        }
+    }
+}
+
+/// The fake record (id 0) is not a real user and cannot be managed
+async fn existing_user(dsrc: &Source, id: i32) -> Result<User, Error> {
+    if id == 0 {
+        return Err(Error::UserNotFound)
+    }
+    user::get_user_by_id(dsrc, id).await?.ok_or(Error::UserNotFound)
+}
+
+pub async fn list_users(_admin: AdminAuthenticated, Query(search): Query<UserSearch>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<UserPage>, Error> {
+    let limit = search.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = search.offset.unwrap_or(0);
+
+    let users = user::list_users(&dsrc, search.search.as_deref(), limit, offset).await?;
+
+    Ok(Json(UserPage {
+        users: users.into_iter().map(AdminUser::from).collect(),
+        limit,
+        offset
+    }))
+}
+
+pub async fn view_user(_admin: AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<AdminUser>, Error> {
+    Ok(Json(existing_user(&dsrc, id).await?.into()))
+}
+
+/// Records an admin action on a user, with the admin as actor
+async fn record_admin_action(dsrc: &Source, kind: AuditKind, admin: &AccessToken, user_usph: &str) {
+    record(dsrc, NewAuditEvent::new(kind).user(user_usph).actor(&admin.sub)).await;
+}
+
+async fn set_disabled(dsrc: &Source, admin: &AccessToken, id: i32, disabled: bool) -> Result<AdminUser, Error> {
+    let user = user::set_disabled(dsrc, existing_user(dsrc, id).await?, disabled).await?;
+    let kind = if disabled { AuditKind::UserDisabled } else { AuditKind::UserEnabled };
+    record_admin_action(dsrc, kind, admin, &user.usp_hex).await;
+
+    Ok(user.into())
+}
+
+/// Disabling also revokes all refresh tokens, access tokens remain valid until they expire
+pub async fn disable_user(AdminAuthenticated(admin): AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<AdminUser>, Error> {
+    Ok(Json(set_disabled(&dsrc, &admin, id, true).await?))
+}
+
+pub async fn enable_user(AdminAuthenticated(admin): AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<AdminUser>, Error> {
+    Ok(Json(set_disabled(&dsrc, &admin, id, false).await?))
+}
+
+/// Revokes all refresh tokens of the user
+pub async fn logout_user(AdminAuthenticated(admin): AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<(), Error> {
+    let user = existing_user(&dsrc, id).await?;
+    delete_user_families(&dsrc, &user.usp_hex).await?;
+    record_admin_action(&dsrc, AuditKind::UserLoggedOut, &admin, &user.usp_hex).await;
+
+    Ok(())
+}
+
+pub async fn delete_user(AdminAuthenticated(admin): AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<(), Error> {
+    let user = existing_user(&dsrc, id).await?;
+    if user.usp_hex == admin.sub {
+        return Err(Error::IncorrectField("admins cannot delete themselves".to_string()))
+    }
+    user::delete_user(&dsrc, &user).await?;
+    record_admin_action(&dsrc, AuditKind::UserDeleted, &admin, &user.usp_hex).await;
+
+    Ok(())
+}
+
+/// Everything stored about the user, for a data subject access request
+pub async fn export_user_data(AdminAuthenticated(admin): AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<UserExport>, Error> {
+    let user = existing_user(&dsrc, id).await?;
+    let export = export_user(&dsrc, &user.usp_hex).await?;
+    record_admin_action(&dsrc, AuditKind::UserExported, &admin, &user.usp_hex).await;
+
+    Ok(Json(export))
+}
+
+/// Deletes the user and everything tied to them, keeping only pseudonymized audit events
+pub async fn erase_user_data(AdminAuthenticated(admin): AdminAuthenticated, Path(id): Path<i32>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<Erasure>, Error> {
+    let user = existing_user(&dsrc, id).await?;
+    if user.usp_hex == admin.sub {
+        return Err(Error::IncorrectField("admins cannot erase themselves".to_string()))
+    }
+    let erasure = erase_user(&dsrc, &user.usp_hex, Some(&admin.sub)).await?;
+
+    Ok(Json(erasure))
+}
+
+/// Newest events first, optionally only of one kind or user
+pub async fn list_audit_events(_admin: AdminAuthenticated, Query(search): Query<AuditSearch>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<AuditPage>, Error> {
+    let limit = search.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let offset = search.offset.unwrap_or(0);
+    let user_usph = search.user.as_deref().map(usp_hex);
+
+    let events = list_events(&dsrc, search.kind.as_deref(), user_usph.as_deref(), limit, offset).await?;
+
+    Ok(Json(AuditPage {
+        events,
+        limit,
+        offset
+    }))
+}
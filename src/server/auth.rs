@@ -1,28 +1,73 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 use axum::extract::Extension;
 use axum::Json;
+use serde::de::DeserializeOwned;
 use opaquebind::server::{login_server, login_server_finish, register_server, register_server_finish};
 
+use crate::auth::throttle::{check_login_allowed, reset_login_failures, start_login_attempt};
+use crate::auth::tokens::{AccessToken, AMR_MFA, AMR_PASSWORD};
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
 use crate::data::key::{get_opaque_private, get_opaque_public};
 use crate::data::kv::KeyValue;
+use crate::data::refresh::delete_user_families;
 use crate::data::source::Source;
+use crate::data::totp::get_totp_by_usph;
 use crate::data::user;
-use crate::data::user::{new_user_return_id, User};
+use crate::data::webauthn::get_passkeys_by_usph;
+use crate::data::user::{new_user_return_id, upsert_user_row, User};
 use crate::error::{Error};
-use crate::error::BadFlow::ExpiredAuthId;
-use crate::server::models::{FinishLogin, FinishRegister, FlowUser, PasswordRequest, PasswordResponse, SavedState};
+use crate::error::BadFlow::{ExpiredAuthId, ExpiredMfaId};
+use crate::mail::{DynMailer, valid_address};
+use crate::server::email::{send_email_in_use, send_verification};
+use crate::server::ip::ClientIp;
+use crate::server::models::{ChangePasswordResponse, ChangeState, FinishLogin, FinishLoginResponse, FinishRegister, FlowUser, PasswordRequest, PasswordResponse, PendingMfa, SavedState, StartChangePassword};
 use crate::utility;
 use crate::utility::{usp_hex};
 
-pub async fn start_login(Json(login_start): Json<PasswordRequest>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<PasswordResponse>, Error> {
-    let private_key = get_opaque_private(&dsrc).await?;
+/// Login age after which enrolling a second factor requires logging in again
+const ENROLL_LOGIN_MAX_AGE: u64 = 10 * 60;
+
+/// Counts the outcomes of both steps of OPAQUE logins
+fn count_login<T>(step: &'static str, result: &Result<T, Error>) {
+    let outcome = match result {
+        Ok(_) => "success",
+        Err(Error::IncorrectCredentials) => "failure",
+        Err(Error::LoginThrottled(_)) => "throttled",
+        Err(_) => "error"
+    };
+    metrics::counter!("tiauth_login_total", 1, "step" => step, "outcome" => outcome);
+}
+
+/// Gets and deletes the state stored at key, so that each step of a flow can only be finished once
+pub(super) async fn take_state<T: DeserializeOwned>(dsrc: &Source, key: &str) -> Result<T, Error> {
+    let state: T = dsrc.kv.get_json(key).await?
+        .ok_or(Error::BadFlow(ExpiredAuthId))?;
+    if !dsrc.kv.delete(key).await? {
+        return Err(Error::BadFlow(ExpiredAuthId))
+    }
+    Ok(state)
+}
+
+pub async fn start_login(Json(login_start): Json<PasswordRequest>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<Json<PasswordResponse>, Error> {
+    let result = login_server_response(&dsrc, login_start, &ip).await;
+    count_login("start", &result);
+    Ok(Json(result?))
+}
+
+async fn login_server_response(dsrc: &Source, login_start: PasswordRequest, ip: &IpAddr) -> Result<PasswordResponse, Error> {
+    let private_key = get_opaque_private(dsrc).await?;
 
     let user_usph = usp_hex(&login_start.username);
+    check_login_allowed(dsrc, &user_usph, ip).await?;
 
-    let password_file = user::get_user_by_usph(&dsrc, &user_usph).await?.unwrap_or(
-        // id 0 is the fake record
-        user::get_user_by_id(&dsrc, 0).await?.ok_or(Error::RequiredNotExists)?
-    ).password_file;
+    // id 0 is the fake record, always retrieved so response times do not depend on whether the user exists
+    let fake_record = user::get_user_by_id(dsrc, 0).await?.ok_or(Error::RequiredNotExists)?;
+    let password_file = match user::get_user_by_usph(dsrc, &user_usph).await? {
+        // Disabled users get the fake record as well, so they cannot be told apart from unknown users
+        Some(user) if !user.disabled => user.password_file,
+        _ => fake_record.password_file
+    };
 
     let auth_id = utility::random_time_hash_hex(Some(user_usph.as_bytes()));
 
@@ -34,31 +79,118 @@ pub async fn start_login(Json(login_start): Json<PasswordRequest>, Extension(dsr
 
     dsrc.kv.store_json(&auth_id, &saved_state, 60).await?;
 
-    let x = PasswordResponse {
+    Ok(PasswordResponse {
         server_message: response,
         auth_id
-    };
-    Ok(Json(x))
+    })
 }
 
-pub async fn finish_login(Json(login_finish): Json<FinishLogin>, Extension(dsrc): Extension<Arc<Source>>) -> Result<(), Error> {
-    let saved_state: SavedState = dsrc.kv.get_json(&login_finish.auth_id).await?
-        .ok_or(Error::BadFlow(ExpiredAuthId))?;
+pub(super) const PENDING_MFA_EXP: usize = 5 * 60;
+
+async fn mfa_methods(dsrc: &Source, user_usph: &str) -> Result<Vec<String>, Error> {
+    let mut methods = vec![];
+    if get_totp_by_usph(dsrc, user_usph).await?.is_some() {
+        methods.push("totp".to_owned());
+    }
+    if !get_passkeys_by_usph(dsrc, user_usph).await?.is_empty() {
+        methods.push("webauthn".to_owned());
+    }
+    Ok(methods)
+}
+
+/// Enrolling a second factor requires a recent login, which must have used a second factor if the user
+/// already has one, so that a token alone cannot add or replace one
+pub(super) async fn check_enrollment_allowed(dsrc: &Source, claims: &AccessToken) -> Result<(), Error> {
+    if !claims.recent_login(ENROLL_LOGIN_MAX_AGE, utility::utc_timestamp()) {
+        return Err(Error::LoginRequired)
+    }
+    if !claims.multi_factor() && !mfa_methods(dsrc, &claims.sub).await?.is_empty() {
+        return Err(Error::LoginRequired)
+    }
+    Ok(())
+}
+
+/// Checks the OPAQUE login of a user, counting failures towards throttling. Returns the session key.
+async fn finish_password_login(dsrc: &Source, user_usph: &str, ip: &IpAddr, client_request: String, state: String) -> Result<String, Error> {
+    let result = check_password_login(dsrc, user_usph, ip, client_request, state).await;
+    count_login("finish", &result);
+    result
+}
+
+async fn check_password_login(dsrc: &Source, user_usph: &str, ip: &IpAddr, client_request: String, state: String) -> Result<String, Error> {
+    start_login_attempt(dsrc, user_usph, ip).await?;
+    match login_server_finish(client_request, state) {
+        Ok(session_key) => {
+            reset_login_failures(dsrc, user_usph, ip).await?;
+            Ok(session_key)
+        }
+        Err(_) => {
+            record(dsrc, NewAuditEvent::new(AuditKind::LoginFailed).user(user_usph).ip(ip)).await;
+            Err(Error::IncorrectCredentials)
+        }
+    }
+}
+
+/// For users with a second factor the `FlowUser` is only stored once that factor is verified, see
+/// `complete_mfa`
+pub async fn finish_login(Json(login_finish): Json<FinishLogin>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<Json<FinishLoginResponse>, Error> {
+    let saved_state: SavedState = take_state(&dsrc, &login_finish.auth_id).await?;
     let user_usph = usp_hex(&login_finish.username);
     if user_usph != saved_state.user_usph {
       return Err(Error::IncorrectFinishUsername)
     };
-    let session_key = login_server_finish(login_finish.client_request, saved_state.state)?;
+    let session_key = finish_password_login(&dsrc, &user_usph, &ip, login_finish.client_request, saved_state.state).await?;
     let auth_time = utility::utc_timestamp();
     let flow_user = FlowUser {
         flow_id: login_finish.flow_id,
         user_usph,
-        auth_time
+        auth_time,
+        amr: vec![AMR_PASSWORD.to_owned()]
     };
 
+    let mfa_methods = mfa_methods(&dsrc, &flow_user.user_usph).await?;
+    let event = NewAuditEvent::new(AuditKind::Login)
+        .user(&flow_user.user_usph)
+        .ip(&ip)
+        .detail("mfa_required", !mfa_methods.is_empty());
+    record(&dsrc, event).await;
+    if !mfa_methods.is_empty() {
+        let mfa_id = utility::random_time_hash_hex(Some(flow_user.user_usph.as_bytes()));
+        let pending = PendingMfa {
+            flow_user,
+            session_key
+        };
+        dsrc.kv.store_json(&mfa_id, &pending, PENDING_MFA_EXP).await?;
+
+        return Ok(Json(FinishLoginResponse {
+            mfa_required: true,
+            mfa_id: Some(mfa_id),
+            mfa_methods
+        }))
+    }
+
     dsrc.kv.store_json(&session_key, &flow_user, 60).await?;
 
-    Ok(())
+    Ok(Json(FinishLoginResponse {
+        mfa_required: false,
+        mfa_id: None,
+        mfa_methods
+    }))
+}
+
+/// Stores the `FlowUser` of a login whose second factor was verified with the given method
+pub(super) async fn complete_mfa(dsrc: &Source, mfa_id: &str, pending: PendingMfa, amr: &str) -> Result<(), Error> {
+    // Ensures a pending login can only be completed once
+    if !dsrc.kv.delete(mfa_id).await? {
+        return Err(Error::BadFlow(ExpiredMfaId))
+    }
+
+    let mut flow_user = pending.flow_user;
+    flow_user.amr.push(amr.to_owned());
+    flow_user.amr.push(AMR_MFA.to_owned());
+    record(dsrc, NewAuditEvent::new(AuditKind::MfaCompleted).user(&flow_user.user_usph).detail("method", amr)).await;
+
+    dsrc.kv.store_json(&pending.session_key, &flow_user, 60).await
 }
 
 pub async fn start_register(Json(register_start): Json<PasswordRequest>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<PasswordResponse>, Error> {
@@ -83,23 +215,144 @@ pub async fn start_register(Json(register_start): Json<PasswordRequest>, Extensi
     Ok(Json(x))
 }
 
-pub async fn finish_register(Json(login_finish): Json<FinishRegister>, Extension(dsrc): Extension<Arc<Source>>) -> Result<(), Error> {
-    let saved_state: SavedState = dsrc.kv.get_json(&login_finish.auth_id).await?
-        .ok_or(Error::BadFlow(ExpiredAuthId))?;
+/// Usernames have to be unique, so registering reveals whether one is taken, without any password.
+/// This is only slowed down by the rate limit on `/register/start/`. An address that is already in use
+/// is not added to the new user. Its owner is told by mail instead, so that registering does not also
+/// reveal which addresses have an account.
+pub async fn finish_register(Json(login_finish): Json<FinishRegister>, Extension(dsrc): Extension<Arc<Source>>, Extension(mailer): Extension<DynMailer>) -> Result<(), Error> {
+    let saved_state: SavedState = take_state(&dsrc, &login_finish.auth_id).await?;
     let user_usph = usp_hex(&login_finish.username);
     if user_usph != saved_state.user_usph {
         return Err(Error::IncorrectFinishUsername)
     };
 
+    if user::get_user_by_usph(&dsrc, &user_usph).await?.is_some() {
+        return Err(Error::UserExists)
+    }
+
     let password_file = register_server_finish(login_finish.client_request, saved_state.state)?;
 
+    let mut email = login_finish.email;
+    if let Some(address) = &email {
+        if !valid_address(address) {
+            return Err(Error::IncorrectField("invalid email address".to_owned()))
+        }
+        if user::get_user_by_verified_email(&dsrc, address).await?.is_some() {
+            send_email_in_use(mailer.clone(), address);
+            email = None;
+        }
+    }
+
     let new_user = User {
         usp_hex: user_usph,
         id: 0,
-        password_file
+        password_file,
+        email,
+        email_verified: false,
+        name: None,
+        admin: false,
+        disabled: false
     };
 
     let _ = new_user_return_id(&dsrc, &new_user).await?;
+    record(&dsrc, NewAuditEvent::new(AuditKind::Register).user(&new_user.usp_hex)).await;
+
+    if let Some(email) = &new_user.email {
+        send_verification(&dsrc, mailer, &new_user.usp_hex, email).await?;
+    }
+
+    Ok(())
+}
+
+/// Proves the user knows their current password by finishing an OPAQUE login started with
+/// `/login/start/`, returning the user's usp_hex. Fails the same way for wrong passwords as for
+/// unknown users, which were given the fake record.
+pub(super) async fn prove_login(dsrc: &Source, auth_id: &str, username: &str, client_request: String, ip: &IpAddr) -> Result<String, Error> {
+    let saved_state: SavedState = take_state(dsrc, auth_id).await?;
+    let user_usph = usp_hex(username);
+    if user_usph != saved_state.user_usph {
+        return Err(Error::IncorrectFinishUsername)
+    };
+    let _ = finish_password_login(dsrc, &user_usph, ip, client_request, saved_state.state).await?;
+
+    Ok(user_usph)
+}
 
+/// Sessions authenticated with the old password should not outlive it, so all refresh token
+/// families of the user are removed
+pub(super) async fn replace_password_file(dsrc: &Source, mut user: User, password_file: String) -> Result<(), Error> {
+    user.password_file = password_file;
+    upsert_user_row(dsrc, &user).await?;
+    delete_user_families(dsrc, &user.usp_hex).await?;
+    record(dsrc, NewAuditEvent::new(AuditKind::PasswordChanged).user(&user.usp_hex)).await;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Finishes an OPAQUE login started with `/login/start/` and immediately starts re-registration.
+/// The password is only replaced once the registration is completed with `finish_change_password`.
+/// Users with a second factor have to verify it in between, like when logging in, using the returned
+/// `mfa_id`.
+pub async fn start_change_password(Json(change_start): Json<StartChangePassword>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<Json<ChangePasswordResponse>, Error> {
+    let user_usph = prove_login(&dsrc, &change_start.auth_id, &change_start.username, change_start.client_request, &ip).await?;
+
+    let public_key = get_opaque_public(&dsrc).await?;
+    let auth_id = utility::random_time_hash_hex(Some(user_usph.as_bytes()));
+
+    let (response, register_state) = register_server(change_start.new_client_request, public_key)?;
+
+    // The flow user is stored under a key only known to the server, so it cannot be used as an OAuth code
+    let mfa_methods = mfa_methods(&dsrc, &user_usph).await?;
+    let (mfa_id, mfa_key, exp) = if mfa_methods.is_empty() {
+        (None, None, 60)
+    } else {
+        let mfa_id = utility::random_time_hash_hex(Some(user_usph.as_bytes()));
+        let mfa_key = utility::random_time_hash_hex(Some(user_usph.as_bytes()));
+        let pending = PendingMfa {
+            flow_user: FlowUser {
+                flow_id: "".to_owned(),
+                user_usph: user_usph.clone(),
+                auth_time: utility::utc_timestamp(),
+                amr: vec![AMR_PASSWORD.to_owned()]
+            },
+            session_key: mfa_key.clone()
+        };
+        dsrc.kv.store_json(&mfa_id, &pending, PENDING_MFA_EXP).await?;
+        (Some(mfa_id), Some(mfa_key), PENDING_MFA_EXP)
+    };
+
+    let change_state = ChangeState {
+        user_usph,
+        register_state,
+        mfa_key
+    };
+
+    dsrc.kv.store_json(&auth_id, &change_state, exp).await?;
+
+    Ok(Json(ChangePasswordResponse {
+        server_message: response,
+        auth_id,
+        mfa_id,
+        mfa_methods
+    }))
+}
+
+pub async fn finish_change_password(Json(change_finish): Json<FinishRegister>, Extension(dsrc): Extension<Arc<Source>>) -> Result<(), Error> {
+    let change_state: ChangeState = take_state(&dsrc, &change_finish.auth_id).await?;
+    let user_usph = usp_hex(&change_finish.username);
+    if user_usph != change_state.user_usph {
+        return Err(Error::IncorrectFinishUsername)
+    };
+    if let Some(mfa_key) = &change_state.mfa_key {
+        let flow_user: FlowUser = take_state(&dsrc, mfa_key).await
+            .map_err(|_| Error::BadFlow(ExpiredMfaId))?;
+        if flow_user.user_usph != user_usph || !flow_user.amr.iter().any(|amr| amr == AMR_MFA) {
+            return Err(Error::BadFlow(ExpiredMfaId))
+        }
+    }
+
+    let user = user::get_user_by_usph(&dsrc, &user_usph).await?
+        .ok_or(Error::RequiredNotExists)?;
+
+    let password_file = register_server_finish(change_finish.client_request, change_state.register_state)?;
+    replace_password_file(&dsrc, user, password_file).await
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use axum::extract::{Extension, FromRequest, RequestParts};
+use axum::http::header::AUTHORIZATION;
+use crate::auth::tokens::{AccessToken, ACCOUNT_SCOPE, ADMIN_SCOPE, verify_access_token};
+use crate::data::source::Source;
+use crate::data::user;
+use crate::error::Error;
+
+/// Extracts and verifies the access token from an `Authorization: Bearer` header
+pub struct Authenticated(pub AccessToken);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Authenticated {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(dsrc) = Extension::<Arc<Source>>::from_request(req).await
+            .map_err(|_| Error::RequiredNotExists)?;
+
+        let access_token = req.headers()
+            .and_then(|headers| headers.get(AUTHORIZATION))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(Error::Unauthorized)?;
+
+        let claims = verify_access_token(&dsrc, access_token).await
+            .map_err(|_| Error::Unauthorized)?;
+
+        Ok(Authenticated(claims))
+    }
+}
+
+/// Requires the admin scope, and that the user is still an admin that is not disabled
+pub struct AdminAuthenticated(pub AccessToken);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AdminAuthenticated {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Authenticated(claims) = Authenticated::from_request(req).await?;
+        if !claims.has_scope(ADMIN_SCOPE) {
+            return Err(Error::Forbidden)
+        }
+
+        let Extension(dsrc) = Extension::<Arc<Source>>::from_request(req).await
+            .map_err(|_| Error::RequiredNotExists)?;
+        match user::get_user_by_usph(&dsrc, &claims.sub).await? {
+            Some(user) if user.admin && !user.disabled => Ok(AdminAuthenticated(claims)),
+            _ => Err(Error::Forbidden)
+        }
+    }
+}
+
+/// Requires the account scope, which only first-party clients are granted, and that the user still
+/// exists and is not disabled
+pub struct AccountAuthenticated(pub AccessToken);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AccountAuthenticated {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Authenticated(claims) = Authenticated::from_request(req).await?;
+        if !claims.has_scope(ACCOUNT_SCOPE) {
+            return Err(Error::Forbidden)
+        }
+
+        let Extension(dsrc) = Extension::<Arc<Source>>::from_request(req).await
+            .map_err(|_| Error::RequiredNotExists)?;
+        match user::get_user_by_usph(&dsrc, &claims.sub).await? {
+            Some(user) if !user.disabled => Ok(AccountAuthenticated(claims)),
+            Some(_) => Err(Error::Forbidden),
+            None => Err(Error::UserNotFound)
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use axum::extract::{Extension, Form, Query};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use url::form_urlencoded::Serializer;
+use url::Url;
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
+use crate::data::client::get_client;
+use crate::data::consent::{get_consent, grant_consent};
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::error::BadFlow::{ExpiredCode, ExpiredFlowId};
+use crate::error::Error;
+use crate::server::files::{escape_html, UiFiles};
+use crate::server::ip::ClientIp;
+use crate::server::models::{AuthRequest, FlowUser, OAuthFinish};
+use crate::utility::utc_timestamp;
+
+/// Time the user has to decide on the consent page, the code is kept that long after it is first shown
+const CONSENT_EXP: usize = 5 * 60;
+
+/// Counts views of the consent page for a code, so its expiry is only extended on the first one
+fn consent_pending_key(code: &str) -> String {
+    format!("consent_pending:{}", code)
+}
+
+pub(super) fn flow_url(path: &str, oauth_finish: &OAuthFinish) -> String {
+    let query = Serializer::new(String::new())
+        .append_pair("flow_id", &oauth_finish.flow_id)
+        .append_pair("code", &oauth_finish.code)
+        .finish();
+    format!("{}?{}", path, query)
+}
+
+/// The authorization request and the user that logged in for it
+pub(super) async fn pending_flow(dsrc: &Source, oauth_finish: &OAuthFinish) -> Result<(AuthRequest, FlowUser), Error> {
+    let auth_request: AuthRequest = dsrc.kv.get_json(&oauth_finish.flow_id).await?
+        .ok_or(Error::BadFlow(ExpiredFlowId))?;
+    let flow_user: FlowUser = dsrc.kv.get_json(&oauth_finish.code).await?
+        .filter(|f: &FlowUser| f.flow_id == oauth_finish.flow_id)
+        .ok_or(Error::BadFlow(ExpiredCode))?;
+    Ok((auth_request, flow_user))
+}
+
+pub(super) async fn consent_given(dsrc: &Source, flow_user: &FlowUser, auth_request: &AuthRequest) -> Result<bool, Error> {
+    let requested = auth_request.scope.as_deref().unwrap_or("");
+    Ok(get_consent(dsrc, &flow_user.user_usph, &auth_request.client_id).await?
+        .map(|c| c.covers(requested))
+        .unwrap_or(false))
+}
+
+/// Redirects back to the client with `access_denied`, as defined for authorization errors in RFC 6749.
+/// The registered redirect URI is kept as is, including any query it already has.
+fn oauth_deny_redirect(auth_request: &AuthRequest) -> Result<String, Error> {
+    let mut redirect = Url::parse(&auth_request.redirect_uri).map_err(|_| Error::UnknownClient)?;
+    redirect.query_pairs_mut()
+        .append_pair("error", "access_denied")
+        .append_pair("state", &auth_request.state);
+    Ok(redirect.into())
+}
+
+/// Shows the client and requested scopes, unless the user already agreed to them before
+pub async fn consent_page(Query(oauth_finish): Query<OAuthFinish>, Extension(dsrc): Extension<Arc<Source>>, Extension(ui): Extension<Arc<UiFiles>>) -> Response {
+    match render_consent(oauth_finish, &dsrc, &ui).await {
+        Ok(response) => response,
+        Err(e) => ui.error_page(e).await
+    }
+}
+
+async fn render_consent(oauth_finish: OAuthFinish, dsrc: &Source, ui: &UiFiles) -> Result<Response, Error> {
+    let (auth_request, flow_user) = pending_flow(dsrc, &oauth_finish).await?;
+    if consent_given(dsrc, &flow_user, &auth_request).await? {
+        return Ok(Redirect::to(flow_url("/oauth/callback/", &oauth_finish).parse().unwrap()).into_response())
+    }
+    let client = get_client(dsrc, &auth_request.client_id).await?.ok_or(Error::UnknownClient)?;
+    if dsrc.kv.incr(&consent_pending_key(&oauth_finish.code), CONSENT_EXP).await? == 1 {
+        dsrc.kv.store_json(&oauth_finish.code, &flow_user, CONSENT_EXP).await?;
+    }
+
+    let scopes: String = auth_request.scope.as_deref().unwrap_or("").split_whitespace()
+        .filter(|scope| *scope != "openid")
+        .map(|scope| format!("<li>{}</li>", escape_html(scope)))
+        .collect();
+    let deny_url = oauth_deny_redirect(&auth_request)?;
+
+    let html: Html<String> = ui.render_raw("consent.html", &[
+        ("client_name", &client.name),
+        ("flow_id", &oauth_finish.flow_id),
+        ("code", &oauth_finish.code),
+        ("deny_url", &deny_url)
+    ], &[("scopes", scopes)]).await?;
+    Ok(html.into_response())
+}
+
+/// Remembers the consent, so later authorizations by the client for the same scopes skip the page
+pub async fn allow_consent(Form(oauth_finish): Form<OAuthFinish>, Extension(dsrc): Extension<Arc<Source>>, Extension(ui): Extension<Arc<UiFiles>>, ClientIp(ip): ClientIp) -> Response {
+    match save_consent(oauth_finish, &dsrc, &ip).await {
+        Ok(redirect) => redirect.into_response(),
+        Err(e) => ui.error_page(e).await
+    }
+}
+
+async fn save_consent(oauth_finish: OAuthFinish, dsrc: &Source, ip: &IpAddr) -> Result<Redirect, Error> {
+    let (auth_request, flow_user) = pending_flow(dsrc, &oauth_finish).await?;
+    let scope = auth_request.scope.as_deref().unwrap_or("");
+    grant_consent(dsrc, &flow_user.user_usph, &auth_request.client_id, scope, utc_timestamp() as i32).await?;
+
+    let event = NewAuditEvent::new(AuditKind::ConsentGranted)
+        .user(&flow_user.user_usph)
+        .client(&auth_request.client_id)
+        .ip(ip)
+        .detail("scope", scope);
+    record(dsrc, event).await;
+
+    Ok(Redirect::to(flow_url("/oauth/callback/", &oauth_finish).parse().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::models::AuthRequest;
+    use super::oauth_deny_redirect;
+
+    fn auth_request(redirect_uri: &str) -> AuthRequest {
+        AuthRequest {
+            response_type: "code".to_owned(),
+            client_id: "client".to_owned(),
+            redirect_uri: redirect_uri.to_owned(),
+            state: "a b".to_owned(),
+            code_challenge: "challenge".to_owned(),
+            code_challenge_method: "S256".to_owned(),
+            nonce: "nonce".to_owned(),
+            scope: None
+        }
+    }
+
+    #[test]
+    fn test_oauth_deny_redirect() {
+        assert_eq!(oauth_deny_redirect(&auth_request("https://app/cb")).unwrap(),
+            "https://app/cb?error=access_denied&state=a+b");
+        assert_eq!(oauth_deny_redirect(&auth_request("https://app/cb?x=1")).unwrap(),
+            "https://app/cb?x=1&error=access_denied&state=a+b");
+    }
+}
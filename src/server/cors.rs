@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use axum::body::BoxBody;
+use axum::http::{HeaderValue, Method, Request, Response, StatusCode};
+use axum::http::header::{ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+use axum::response::IntoResponse;
+use tower::{Layer, Service};
+use crate::data::client::list_clients;
+use crate::data::source::Source;
+use crate::error::Error;
+
+/// How long the origins of the client registry are cached, so changes take at most this long to apply
+const ORIGINS_TTL: Duration = Duration::from_secs(60);
+
+type CachedOrigins = Option<(Instant, Arc<HashSet<String>>)>;
+
+/// CORS for endpoints called by registered clients from the browser, like the token endpoint. Only
+/// origins of registered clients are allowed, see `Client::origins`. Requests from other origins are
+/// still handled, but without CORS headers browsers do not expose the response.
+#[derive(Clone)]
+pub struct ClientCorsLayer {
+    dsrc: Arc<Source>,
+    cache: Arc<RwLock<CachedOrigins>>
+}
+
+impl ClientCorsLayer {
+    pub fn new(dsrc: Arc<Source>) -> Self {
+        Self {
+            dsrc,
+            cache: Arc::new(RwLock::new(None))
+        }
+    }
+
+    async fn origins(&self) -> Result<Arc<HashSet<String>>, Error> {
+        if let Some((loaded, origins)) = &*self.cache.read().unwrap() {
+            if loaded.elapsed() < ORIGINS_TTL {
+                return Ok(origins.clone())
+            }
+        }
+        let origins: HashSet<String> = list_clients(&self.dsrc).await?.iter()
+            .flat_map(|c| c.origins())
+            .collect();
+        let origins = Arc::new(origins);
+        *self.cache.write().unwrap() = Some((Instant::now(), origins.clone()));
+        Ok(origins)
+    }
+
+    async fn allowed(&self, origin: &str) -> bool {
+        match self.origins().await {
+            Ok(origins) => origins.contains(origin),
+            Err(e) => {
+                tracing::error!("failed to load client origins: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for ClientCorsLayer {
+    type Service = ClientCors<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientCors {
+            inner,
+            layer: self.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientCors<S> {
+    inner: S,
+    layer: ClientCorsLayer
+}
+
+fn preflight_response(origin: Option<HeaderValue>) -> Response<BoxBody> {
+    let mut response = match origin {
+        Some(origin) => {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            let headers = response.headers_mut();
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST"));
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("authorization, content-type"));
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+            response
+        }
+        None => StatusCode::FORBIDDEN.into_response()
+    };
+    response.headers_mut().append(VARY, HeaderValue::from_static("origin"));
+    response
+}
+
+impl<S, B> Service<Request<B>> for ClientCors<S>
+    where
+        S: Service<Request<B>, Response=Response<BoxBody>, Error=Infallible> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        B: Send + 'static
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // The clone might not be ready, so the instance that was polled is used for this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        let origin = req.headers().get(ORIGIN).cloned();
+        let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+        Box::pin(async move {
+            let origin = match origin {
+                Some(origin) => origin,
+                // Not a cross-origin request from a browser
+                None => return inner.call(req).await
+            };
+            let allowed = match origin.to_str() {
+                Ok(o) => layer.allowed(o).await,
+                Err(_) => false
+            };
+            let allowed_origin = if allowed { Some(origin) } else { None };
+
+            if preflight {
+                return Ok(preflight_response(allowed_origin))
+            }
+            let mut response = inner.call(req).await?;
+            if let Some(origin) = allowed_origin {
+                response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            }
+            response.headers_mut().append(VARY, HeaderValue::from_static("origin"));
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+use axum::extract::{Extension, Query};
+use axum::Json;
+use opaquebind::server::{register_server, register_server_finish};
+use crate::auth::email::{check_email_token, EmailPurpose, new_email_token, use_email_token};
+use crate::config::ISS;
+use crate::data::key::get_opaque_public;
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::data::user;
+use crate::data::user::upsert_user_row;
+use crate::error::Error;
+use crate::mail::{DynMailer, Mail, valid_address};
+use crate::server::auth::{prove_login, replace_password_file, take_state};
+use crate::server::ip::ClientIp;
+use crate::server::models::{FinishReset, PasswordResponse, ResetState, SetEmail, StartRecovery, StartReset, VerifyEmail};
+use crate::utility;
+
+/// Mail is sent in the background, so that response times do not depend on whether mail was sent
+fn send_in_background(mailer: DynMailer, mail: Mail) {
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(mail).await {
+            tracing::error!("failed to send mail: {}", e);
+        }
+    });
+}
+
+pub(super) async fn send_verification(dsrc: &Source, mailer: DynMailer, user_usph: &str, email: &str) -> Result<(), Error> {
+    let token = new_email_token(dsrc, EmailPurpose::Verify, user_usph, email).await?;
+    let mail = Mail {
+        to: email.to_owned(),
+        subject: "Verify your email address".to_owned(),
+        body: format!("Open the following link to verify your email address:\n\n{}/email/verify/?token={}\n", ISS, token)
+    };
+    send_in_background(mailer, mail);
+
+    Ok(())
+}
+
+/// Sent instead of a verification mail when someone registers with or sets an address that is already
+/// verified by another account. The request itself succeeds without the address, so it does not reveal
+/// the address is taken.
+pub(super) fn send_email_in_use(mailer: DynMailer, email: &str) {
+    let mail = Mail {
+        to: email.to_owned(),
+        subject: "Your email address was used for another account".to_owned(),
+        body: "Someone tried to add your email address, which already belongs to your account, to another \
+            account. It was not added.\n\n\
+            If this was you, log in with your existing account instead, or request a password reset if you \
+            forgot your password. Otherwise you can ignore this mail.\n".to_owned()
+    };
+    send_in_background(mailer, mail);
+}
+
+/// Requires a login started with `/login/start/`, like changing the password. An address verified by
+/// another account is handled like in registration, see `send_email_in_use`.
+pub async fn set_email(Json(set_email): Json<SetEmail>, Extension(dsrc): Extension<Arc<Source>>, Extension(mailer): Extension<DynMailer>, ClientIp(ip): ClientIp) -> Result<(), Error> {
+    let user_usph = prove_login(&dsrc, &set_email.auth_id, &set_email.username, set_email.client_request, &ip).await?;
+    if !valid_address(&set_email.email) {
+        return Err(Error::IncorrectField("invalid email address".to_owned()))
+    }
+
+    let mut user = user::get_user_by_usph(&dsrc, &user_usph).await?
+        .ok_or(Error::RequiredNotExists)?;
+    if let Some(owner) = user::get_user_by_verified_email(&dsrc, &set_email.email).await? {
+        if owner.id != user.id {
+            send_email_in_use(mailer, &set_email.email);
+            return Ok(())
+        }
+    }
+    user.email = Some(set_email.email.clone());
+    user.email_verified = false;
+    upsert_user_row(&dsrc, &user).await?;
+
+    send_verification(&dsrc, mailer, &user_usph, &set_email.email).await
+}
+
+pub async fn verify_email(Query(verify): Query<VerifyEmail>, Extension(dsrc): Extension<Arc<Source>>) -> Result<&'static str, Error> {
+    let email_token = check_email_token(&dsrc, &verify.token, EmailPurpose::Verify).await?;
+    use_email_token(&dsrc, &email_token.id).await?;
+
+    let mut user = user::get_user_by_usph(&dsrc, &email_token.user_usph).await?
+        .ok_or(Error::InvalidEmailToken)?;
+    // The address might have been changed after the mail was sent
+    if user.email.as_deref() != Some(email_token.email.as_str()) {
+        return Err(Error::InvalidEmailToken)
+    }
+    user.email_verified = true;
+    upsert_user_row(&dsrc, &user).await?;
+
+    Ok("Email address verified.")
+}
+
+/// Always succeeds, so that it cannot be used to find out which addresses belong to an account
+pub async fn start_recovery(Json(recovery): Json<StartRecovery>, Extension(dsrc): Extension<Arc<Source>>, Extension(mailer): Extension<DynMailer>) -> Result<(), Error> {
+    let user = match user::get_user_by_verified_email(&dsrc, &recovery.email).await? {
+        Some(user) => user,
+        _ => return Ok(())
+    };
+
+    let token = new_email_token(&dsrc, EmailPurpose::Reset, &user.usp_hex, &recovery.email).await?;
+    let mail = Mail {
+        to: recovery.email,
+        subject: "Reset your password".to_owned(),
+        body: format!("Open the following link to choose a new password:\n\n{}/credentials/reset.html?token={}\n\n\
+            If you did not request this, you can ignore this mail.\n", ISS, token)
+    };
+    send_in_background(mailer, mail);
+
+    Ok(())
+}
+
+/// Starts OPAQUE re-registration for the user the reset token was sent to. The token is only used
+/// up once the registration is finished.
+pub async fn start_reset(Json(reset_start): Json<StartReset>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<PasswordResponse>, Error> {
+    let email_token = check_email_token(&dsrc, &reset_start.token, EmailPurpose::Reset).await?;
+    let user = user::get_user_by_usph(&dsrc, &email_token.user_usph).await?
+        .ok_or(Error::InvalidEmailToken)?;
+    if user.disabled || !user.email_verified || user.email.as_deref() != Some(email_token.email.as_str()) {
+        return Err(Error::InvalidEmailToken)
+    }
+
+    let public_key = get_opaque_public(&dsrc).await?;
+    let auth_id = utility::random_time_hash_hex(Some(user.usp_hex.as_bytes()));
+
+    let (response, register_state) = register_server(reset_start.client_request, public_key)?;
+    let reset_state = ResetState {
+        user_usph: user.usp_hex,
+        register_state,
+        token_id: email_token.id
+    };
+
+    dsrc.kv.store_json(&auth_id, &reset_state, 60).await?;
+
+    let x = PasswordResponse {
+        server_message: response,
+        auth_id
+    };
+    Ok(Json(x))
+}
+
+/// The user is checked again, as they may have been disabled since the reset was started
+pub async fn finish_reset(Json(reset_finish): Json<FinishReset>, Extension(dsrc): Extension<Arc<Source>>) -> Result<(), Error> {
+    let reset_state: ResetState = take_state(&dsrc, &reset_finish.auth_id).await?;
+    let user = user::get_user_by_usph(&dsrc, &reset_state.user_usph).await?
+        .ok_or(Error::RequiredNotExists)?;
+    if user.disabled {
+        return Err(Error::InvalidEmailToken)
+    }
+
+    let password_file = register_server_finish(reset_finish.client_request, reset_state.register_state)?;
+    use_email_token(&dsrc, &reset_state.token_id).await?;
+
+    replace_password_file(&dsrc, user, password_file).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::mail::MemoryMailer;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_email_in_use() {
+        let mailer = Arc::new(MemoryMailer::default());
+        send_email_in_use(mailer.clone(), "taken@example.com");
+
+        for _ in 0..100 {
+            if !mailer.sent().is_empty() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "taken@example.com");
+        assert!(!sent[0].body.contains("token="));
+    }
+}
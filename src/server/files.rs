@@ -1,23 +1,128 @@
-use axum::body::{Body, BoxBody, boxed};
-use axum::http::{Request, StatusCode, Uri};
-use axum::response::Response;
-use tower::ServiceExt;
-use tower_http::services::ServeDir;
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use axum::extract::Extension;
+use axum::http::{header, HeaderValue, StatusCode, Uri};
+use axum::response::{Html, IntoResponse, Response};
+use include_dir::{Dir, include_dir};
+use crate::config::UiConfig;
+use crate::error::Error;
+use crate::server::error_status;
 
-pub async fn serve_static(uri: Uri) -> Result<Response<BoxBody>, (StatusCode, String)> {
-    let res = get_static_file(uri.clone()).await?;
+/// The bundled UI, `ui/pkg` must have been built with wasm-pack before compiling
+static UI: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/ui");
 
-    Ok(res)
+/// Files in the theme directory take precedence over the bundled ones with the same path, so a
+/// deployment can replace `theme.css` or any page without rebuilding
+pub struct UiFiles {
+    theme_dir: Option<PathBuf>
 }
 
-async fn get_static_file(uri: Uri) -> Result<Response<BoxBody>, (StatusCode, String)> {
-    let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+impl UiFiles {
+    pub fn new(config: &UiConfig) -> Self {
+        Self {
+            theme_dir: config.theme_dir.as_ref().map(PathBuf::from)
+        }
+    }
+
+    async fn get(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        // Only plain relative paths, so nothing outside the theme directory can be read
+        if !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+            return None
+        }
+        if let Some(theme_dir) = &self.theme_dir {
+            if let Ok(contents) = tokio::fs::read(theme_dir.join(path)).await {
+                return Some(Cow::Owned(contents))
+            }
+        }
+        UI.get_file(path).map(|f| Cow::Borrowed(f.contents()))
+    }
 
-    match ServeDir::new("credentials").oneshot(req).await {
-        Ok(res) => Ok(res.map(boxed)),
-        Err(err) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", err),
-        )),
+    /// Replaces each `{{name}}` in the page with its value, which is HTML-escaped
+    pub async fn render(&self, page: &str, vars: &[(&str, &str)]) -> Result<Html<String>, Error> {
+        let template = self.get(page).await.ok_or(Error::RequiredNotExists)?;
+        let mut html = String::from_utf8_lossy(&template).into_owned();
+        for (name, value) in vars {
+            html = html.replace(&format!("{{{{{}}}}}", name), &escape_html(value));
+        }
+        Ok(Html(html))
     }
-}
\ No newline at end of file
+
+    /// Like `render`, but for values that are already HTML
+    pub async fn render_raw(&self, page: &str, vars: &[(&str, &str)], raw: &[(&str, String)]) -> Result<Html<String>, Error> {
+        let Html(mut html) = self.render(page, vars).await?;
+        for (name, value) in raw {
+            html = html.replace(&format!("{{{{{}}}}}", name), value);
+        }
+        Ok(Html(html))
+    }
+
+    /// Shows the error to a user in the browser, hiding the details of server errors
+    pub async fn error_page(&self, error: Error) -> Response {
+        let status = error_status(&error);
+        let message = if status.is_server_error() {
+            tracing::error!("{:?}", error);
+            "Something went wrong on our side.".to_owned()
+        } else {
+            error.to_string()
+        };
+        match self.render("error.html", &[("message", &message)]).await {
+            Ok(html) => (status, html).into_response(),
+            Err(_) => (status, message).into_response()
+        }
+    }
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn content_type(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("wasm") => "application/wasm",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream"
+    }
+}
+
+pub async fn serve_static(uri: Uri, Extension(ui): Extension<Arc<UiFiles>>) -> Response {
+    let path = match uri.path().trim_start_matches('/') {
+        "" => "index.html",
+        path => path
+    };
+    match ui.get(path).await {
+        Some(contents) => {
+            let mut response = contents.into_owned().into_response();
+            response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type(path)));
+            response
+        }
+        None => StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_html;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("<a href=\"x\">'&'</a>"), "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+    }
+}
@@ -0,0 +1,23 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::auth::tokens::delete_expired_refresh;
+use crate::config::RefreshGcConfig;
+use crate::data::source::Source;
+
+/// Periodically deletes expired refresh tokens. Running it on several replicas at once is harmless.
+pub fn spawn_refresh_gc(dsrc: Arc<Source>, config: RefreshGcConfig) {
+    if !config.enabled {
+        return
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        loop {
+            interval.tick().await;
+            match delete_expired_refresh(&dsrc, config.batch_size).await {
+                Ok(0) => {}
+                Ok(deleted) => tracing::info!("deleted {} expired refresh tokens", deleted),
+                Err(e) => tracing::warn!("refresh token garbage collection failed: {:?}", e)
+            }
+        }
+    });
+}
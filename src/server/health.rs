@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use crate::data::db::Database;
+use crate::data::key::list_keys;
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::data::user;
+use crate::error::Error;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Key ids 0 (OPAQUE), 1 (token signing) and 2 (symmetric)
+const REQUIRED_KEYS: [i32; 3] = [0, 1, 2];
+
+#[derive(Serialize)]
+pub struct Readiness {
+    ready: bool,
+    /// "ok", "unavailable" or "timed out", the details are only logged
+    checks: BTreeMap<&'static str, &'static str>
+}
+
+/// The process is up and serving requests, the backends are not checked
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn check_keys(dsrc: &Source) -> Result<(), Error> {
+    let keys = list_keys(dsrc).await?;
+    match REQUIRED_KEYS.iter().find(|id| !keys.iter().any(|k| k.id == **id)) {
+        Some(id) => Err(Error::IncorrectField(format!("key {} does not exist", id))),
+        None => Ok(())
+    }
+}
+
+async fn check_fake_record(dsrc: &Source) -> Result<(), Error> {
+    user::get_user_by_id(dsrc, 0).await?.map(|_| ()).ok_or(Error::RequiredNotExists)
+}
+
+/// The endpoint is public, so errors are logged instead of returned
+async fn run_check<F: Future<Output=Result<(), Error>>>(name: &str, check: F) -> &'static str {
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => "ok",
+        Ok(Err(e)) => {
+            tracing::warn!("readiness check {} failed: {}", name, e);
+            "unavailable"
+        }
+        Err(_) => {
+            tracing::warn!("readiness check {} timed out", name);
+            "timed out"
+        }
+    }
+}
+
+/// Ready once both backends are reachable, RedisJSON is loaded and the keys and fake record needed for
+/// logins exist. Responds with 503 otherwise, listing which checks failed.
+pub async fn readyz(Extension(dsrc): Extension<Arc<Source>>) -> (StatusCode, Json<Readiness>) {
+    let mut checks = BTreeMap::new();
+    checks.insert("postgres", run_check("postgres", dsrc.db.ping()).await);
+    checks.insert("redis", run_check("redis", dsrc.kv.ping()).await);
+    checks.insert("keys", run_check("keys", check_keys(&dsrc)).await);
+    checks.insert("fake_record", run_check("fake_record", check_fake_record(&dsrc)).await);
+
+    let ready = checks.values().all(|c| *c == "ok");
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(Readiness { ready, checks }))
+}
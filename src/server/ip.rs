@@ -0,0 +1,59 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, Extension, FromRequest, RequestParts};
+use axum::http::{Extensions, HeaderMap};
+use crate::config::Config;
+use crate::error::Error;
+
+/// Address of the connecting client, see `client_ip`
+pub struct ClientIp(pub IpAddr);
+
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers.get("x-forwarded-for")?
+        .to_str().ok()?
+        .split(',').next()?
+        .trim().parse().ok()
+}
+
+/// Only trusts `X-Forwarded-For` if configured to, as it can otherwise be set by anyone
+pub fn client_ip(headers: &HeaderMap, extensions: &Extensions, trust_forwarded_for: bool) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        if let Some(ip) = forwarded_for(headers) {
+            return Some(ip)
+        }
+    }
+    extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip())
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for ClientIp {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(config) = Extension::<Arc<Config>>::from_request(req).await
+            .map_err(|_| Error::RequiredNotExists)?;
+        let headers = req.headers().ok_or(Error::RequiredNotExists)?;
+        let extensions = req.extensions().ok_or(Error::RequiredNotExists)?;
+
+        client_ip(headers, extensions, config.trust_forwarded_for)
+            .map(ClientIp)
+            .ok_or(Error::RequiredNotExists)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.1".parse().unwrap());
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+
+        assert_eq!(client_ip(&headers, &extensions, true), Some(IpAddr::from([203, 0, 113, 7])));
+        assert_eq!(client_ip(&headers, &extensions, false), Some(IpAddr::from([10, 0, 0, 1])));
+    }
+}
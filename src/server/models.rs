@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{PasskeyAuthentication, PublicKeyCredential, RequestChallengeResponse};
+use crate::data::audit::AuditEvent;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthRequest {
@@ -8,7 +10,10 @@ pub struct AuthRequest {
     pub state: String,
     pub code_challenge: String,
     pub code_challenge_method: String,
-    pub nonce: String
+    pub nonce: String,
+    /// Space-separated
+    #[serde(default)]
+    pub scope: Option<String>
 }
 
 #[derive(Deserialize)]
@@ -41,16 +46,173 @@ pub struct FinishLogin {
 pub struct FlowUser {
     pub user_usph: String,
     pub flow_id: String,
-    pub auth_time: u64
+    pub auth_time: u64,
+    #[serde(default)]
+    pub amr: Vec<String>
+}
+
+#[derive(Serialize)]
+pub struct FinishLoginResponse {
+    pub mfa_required: bool,
+    pub mfa_id: Option<String>,
+    /// Any of these can be used to verify the second factor, "totp" or "webauthn"
+    pub mfa_methods: Vec<String>
+}
+
+/// Stored after a successful password login while the second factor has not been verified yet
+#[derive(Deserialize, Serialize)]
+pub struct PendingMfa {
+    pub flow_user: FlowUser,
+    pub session_key: String
+}
+
+#[derive(Deserialize)]
+pub struct VerifyTotp {
+    pub mfa_id: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PendingTotp {
+    pub secret: String
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTotp {
+    pub code: String
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>
 }
 
 #[derive(Deserialize)]
 pub struct FinishRegister {
     pub auth_id: String,
     pub username: String,
+    pub client_request: String,
+    /// Only used when registering, ignored when changing a password
+    #[serde(default)]
+    pub email: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct StartChangePassword {
+    pub auth_id: String,
+    pub username: String,
+    pub client_request: String,
+    pub new_client_request: String
+}
+
+/// Kept separate from `SavedState` so that a registration started through `/register/start/`
+/// can never be used to finish a password change.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChangeState {
+    pub user_usph: String,
+    pub register_state: String,
+    /// For users with a second factor, the key `complete_mfa` stores the `FlowUser` at once it is verified
+    #[serde(default)]
+    pub mfa_key: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct ChangePasswordResponse {
+    pub server_message: String,
+    pub auth_id: String,
+    /// If set, the second factor has to be verified with this id at `/login/mfa/` before finishing
+    pub mfa_id: Option<String>,
+    pub mfa_methods: Vec<String>
+}
+
+#[derive(Deserialize)]
+pub struct SetEmail {
+    pub auth_id: String,
+    pub username: String,
+    pub client_request: String,
+    pub email: String
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmail {
+    pub token: String
+}
+
+#[derive(Deserialize)]
+pub struct StartRecovery {
+    pub email: String
+}
+
+#[derive(Deserialize)]
+pub struct StartReset {
+    pub token: String,
     pub client_request: String
 }
 
+#[derive(Deserialize)]
+pub struct FinishReset {
+    pub auth_id: String,
+    pub client_request: String
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResetState {
+    pub user_usph: String,
+    pub register_state: String,
+    pub token_id: String
+}
+
+#[derive(Deserialize)]
+pub struct StartWebauthnLogin {
+    pub username: String,
+    pub flow_id: String
+}
+
+#[derive(Serialize)]
+pub struct WebauthnLoginChallenge {
+    pub auth_id: String,
+    pub challenge: RequestChallengeResponse
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct WebauthnLoginState {
+    pub user_usph: String,
+    pub flow_id: String,
+    pub state: PasskeyAuthentication
+}
+
+#[derive(Deserialize)]
+pub struct FinishWebauthnLogin {
+    pub auth_id: String,
+    pub credential: PublicKeyCredential
+}
+
+/// The code takes the place of the OPAQUE session key in the rest of the flow
+#[derive(Serialize)]
+pub struct WebauthnLoginResponse {
+    pub code: String
+}
+
+#[derive(Deserialize)]
+pub struct StartWebauthnMfa {
+    pub mfa_id: String
+}
+
+#[derive(Deserialize)]
+pub struct FinishWebauthnMfa {
+    pub mfa_id: String,
+    pub credential: PublicKeyCredential
+}
+
 #[derive(Deserialize)]
 pub struct OAuthFinish {
     pub flow_id: String,
@@ -71,8 +233,86 @@ pub struct TokenRequest {
 pub struct TokenResponse {
     pub id_token: String,
     pub access_token: String,
-    pub refresh_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
     pub token_type: String,
     pub expires_in: i32,
     pub scope: String,
 }
+
+#[derive(Deserialize)]
+pub struct UserSearch {
+    pub search: Option<String>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>
+}
+
+#[derive(Serialize)]
+pub struct AdminUser {
+    pub id: i32,
+    pub username: String,
+    pub usp_hex: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub admin: bool,
+    pub disabled: bool
+}
+
+#[derive(Serialize)]
+pub struct UserPage {
+    pub users: Vec<AdminUser>,
+    pub limit: u64,
+    pub offset: u64
+}
+
+#[derive(Deserialize)]
+pub struct AuditSearch {
+    pub kind: Option<String>,
+    /// Username, not the usp_hex
+    pub user: Option<String>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    pub limit: u64,
+    pub offset: u64
+}
+
+#[derive(Serialize)]
+pub struct AccountConsent {
+    pub client_id: String,
+    pub scope: String,
+    pub granted_at: i32
+}
+
+#[derive(Serialize)]
+pub struct AccountProfile {
+    pub sub: String,
+    pub username: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: bool
+}
+
+/// The email address is changed with `/email/set/` instead, which requires the password
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateProfile {
+    /// An empty name removes it
+    pub name: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct AccountSession {
+    pub family_id: String,
+    /// Empty for sessions started before tokens were bound to a client
+    pub client_id: String,
+    /// Time of the login that started the session
+    pub issued_at: i32,
+    /// Time the current refresh token was issued
+    pub refreshed_at: i32,
+    pub expires_at: i32
+}
@@ -1,21 +1,50 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 use axum::extract::{Extension, Query};
 use axum::Json;
-use axum::response::{Redirect};
+use axum::response::{IntoResponse, Redirect, Response};
 use url::form_urlencoded::{byte_serialize};
 use encoding::{Encoding, EncoderTrap};
 use encoding::all::ASCII;
 use sha2::{Digest, Sha256};
+use crate::auth::discovery::{discovery_document, DiscoveryDocument};
 use crate::auth::tokens;
-use crate::auth::tokens::{new_token_family, refresh_all_tokens};
+use crate::auth::tokens::{ACCOUNT_SCOPE, ADMIN_SCOPE, new_token_family, refresh_all_tokens, Tokens};
+use crate::config::Config;
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
+use crate::data::client::{Client, get_client};
 use crate::data::kv::KeyValue;
+use crate::server::consent::{consent_given, flow_url, pending_flow};
+use crate::server::files::UiFiles;
+use crate::server::ip::ClientIp;
+use crate::server::ratelimit::check_oauth_client_limit;
 use crate::server::models::{AuthRequest, FlowUser, OAuthFinish, TokenRequest, TokenResponse};
 use crate::data::source::Source;
+use crate::data::user;
 use crate::error::Error;
 use crate::error::BadFlow::{ExpiredFlowId, BadChallenge, ExpiredCode};
 use crate::utility::{enc_b64url, random_time_hash_hex};
 
-pub async fn oauth_endpoint(Query(auth_request): Query<AuthRequest>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Redirect, Error> {
+/// Errors are shown as a page, as the user is sent here by the client
+pub async fn oauth_endpoint(Query(auth_request): Query<AuthRequest>, Extension(dsrc): Extension<Arc<Source>>, Extension(config): Extension<Arc<Config>>, Extension(ui): Extension<Arc<UiFiles>>, ClientIp(ip): ClientIp) -> Response {
+    if let Err(e) = check_oauth_client_limit(&dsrc, &config.rate_limit, &auth_request.client_id, &ip).await {
+        return ui.error_page(e).await
+    }
+    match start_flow(auth_request, &dsrc).await {
+        Ok(redirect) => redirect.into_response(),
+        Err(e) => ui.error_page(e).await
+    }
+}
+
+async fn start_flow(auth_request: AuthRequest, dsrc: &Source) -> Result<Redirect, Error> {
+    // Never redirect to a URI that was not registered for the client
+    let client_allowed = get_client(dsrc, &auth_request.client_id).await?
+        .map(|c| c.redirect_uri_allowed(&auth_request.redirect_uri))
+        .unwrap_or(false);
+    if !client_allowed {
+        return Err(Error::UnknownClient)
+    }
+
     let flow_id = random_time_hash_hex(None);
 
     dsrc.kv.store_json(&flow_id, &auth_request, 1000).await?;
@@ -33,9 +62,19 @@ fn oauth_finish_redirect(auth_request: AuthRequest, code: String) -> String {
     format!("{}?code={}&state={}", redirect, code, state_encoded)
 }
 
-pub async fn oauth_finish(Query(oauth_finish): Query<OAuthFinish>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Redirect, Error> {
-    let auth_request: AuthRequest = dsrc.kv.get_json(&oauth_finish.flow_id).await?
-        .ok_or(Error::BadFlow(ExpiredFlowId))?;
+pub async fn oauth_finish(Query(oauth_finish): Query<OAuthFinish>, Extension(dsrc): Extension<Arc<Source>>, Extension(ui): Extension<Arc<UiFiles>>) -> Response {
+    match finish_flow(oauth_finish, &dsrc).await {
+        Ok(redirect) => redirect.into_response(),
+        Err(e) => ui.error_page(e).await
+    }
+}
+
+/// The code is only sent to the client once the user consented to the requested scopes
+async fn finish_flow(oauth_finish: OAuthFinish, dsrc: &Source) -> Result<Redirect, Error> {
+    let (auth_request, flow_user) = pending_flow(dsrc, &oauth_finish).await?;
+    if !consent_given(dsrc, &flow_user, &auth_request).await? {
+        return Ok(Redirect::to(flow_url("/oauth/consent/", &oauth_finish).parse().unwrap()))
+    }
 
     let redirect_url = oauth_finish_redirect(auth_request, oauth_finish.code);
 
@@ -62,10 +101,59 @@ fn token_request_checks(redirect_uri_token: &str, redirect_uri_auth: &str, clien
     Ok(())
 }
 
-pub async fn token(Json(token_request): Json<TokenRequest>, Extension(dsrc): Extension<Arc<Source>>) -> Result<Json<TokenResponse>, Error> {
-    // TODO clientID check
+pub async fn openid_configuration() -> Json<DiscoveryDocument> {
+    Json(discovery_document())
+}
+
+/// Scopes are granted as requested, except the admin scope, which requires an admin user and a
+/// first-party client, and the account scope, which requires a first-party client
+async fn granted_scope(dsrc: &Source, client: &Client, user_usph: &str, requested: Option<&str>) -> Result<String, Error> {
+    let requested = match requested {
+        Some(requested) => requested,
+        None => return Ok("".to_owned())
+    };
+    let is_admin = client.first_party && user::get_user_by_usph(dsrc, user_usph).await?
+        .map(|u| u.admin && !u.disabled)
+        .unwrap_or(false);
+
+    let scopes: Vec<&str> = requested.split_whitespace()
+        .filter(|scope| match *scope {
+            ADMIN_SCOPE => is_admin,
+            ACCOUNT_SCOPE => client.first_party,
+            _ => true
+        })
+        .collect();
+    Ok(scopes.join(" "))
+}
+
+fn count_grant(grant_type: &str, result: &Result<Tokens, Error>) {
+    let grant_type = match grant_type {
+        "authorization_code" | "refresh_token" => grant_type.to_owned(),
+        _ => "unsupported".to_owned()
+    };
+    let outcome = if result.is_ok() { "success" } else { "error" };
+    metrics::counter!("tiauth_token_grants_total", 1, "grant_type" => grant_type, "outcome" => outcome);
+}
+
+pub async fn token(Json(token_request): Json<TokenRequest>, Extension(dsrc): Extension<Arc<Source>>, Extension(config): Extension<Arc<Config>>, ClientIp(ip): ClientIp) -> Result<Json<TokenResponse>, Error> {
+    check_oauth_client_limit(&dsrc, &config.rate_limit, &token_request.client_id, &ip).await?;
+    let grant_type = token_request.grant_type.clone();
+    let tokens = grant_tokens(&dsrc, token_request, &ip).await;
+    count_grant(&grant_type, &tokens);
+    let tokens = tokens?;
+
+    Ok(Json(TokenResponse{
+        id_token: tokens.id_token,
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        token_type: "Bearer".to_string(),
+        expires_in: tokens::ACCESS_EXP as i32,
+        scope: tokens.returned_scope
+    }))
+}
 
-    let tokens = if token_request.grant_type == "authorization_code" {
+async fn grant_tokens(dsrc: &Source, token_request: TokenRequest, ip: &IpAddr) -> Result<Tokens, Error> {
+    if token_request.grant_type == "authorization_code" {
         let redirect_uri_token = token_request.redirect_uri.ok_or(Error::MissingFieldTokenRequest)?;
         let code_verifier = token_request.code_verifier.ok_or(Error::MissingFieldTokenRequest)?;
         let code = token_request.code.ok_or(Error::MissingFieldTokenRequest)?;
@@ -79,25 +167,23 @@ pub async fn token(Json(token_request): Json<TokenRequest>, Extension(dsrc): Ext
         &token_request.client_id, &auth_request.client_id, &code_verifier,
                                        &auth_request.code_challenge)?;
 
-        // TODO implement scope
-        let scope = "test".to_string();
-
-        new_token_family(&dsrc, flow_user.user_usph, scope, auth_request.nonce, flow_user.auth_time).await
+        let client = get_client(dsrc, &token_request.client_id).await?.ok_or(Error::UnknownClient)?;
+        let scope = granted_scope(dsrc, &client, &flow_user.user_usph, auth_request.scope.as_deref()).await?;
+
+        let event = NewAuditEvent::new(AuditKind::TokenIssued)
+            .user(&flow_user.user_usph)
+            .client(&client.client_id)
+            .ip(ip)
+            .detail("scope", scope.as_str());
+        let tokens = new_token_family(dsrc, &client, flow_user.user_usph, scope, auth_request.nonce, flow_user.auth_time, flow_user.amr).await?;
+        record(dsrc, event).await;
+        Ok(tokens)
     } else if token_request.grant_type == "refresh_token" {
         tracing::debug!("refresh_token request");
         let old_refresh_token = token_request.refresh_token.ok_or(Error::MissingFieldTokenRequest)?;
 
-        refresh_all_tokens(&dsrc, old_refresh_token).await
+        refresh_all_tokens(dsrc, &token_request.client_id, old_refresh_token).await
     } else {
         Err(Error::IncorrectField("token_request only supports authorization_code and refresh_token".to_string()))
-    }?;
-    
-    Ok(Json(TokenResponse{
-        id_token: tokens.id_token,
-        access_token: tokens.access_token,
-        refresh_token: tokens.refresh_token,
-        token_type: "Bearer".to_string(),
-        expires_in: tokens::ACCESS_EXP as i32,
-        scope: tokens.returned_scope
-    }))
+    }
 }
\ No newline at end of file
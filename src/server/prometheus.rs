@@ -0,0 +1,90 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use axum::{AddExtensionLayer, Router};
+use axum::body::BoxBody;
+use axum::extract::{Extension, MatchedPath};
+use axum::http::{Request, Response};
+use axum::routing::get;
+use axum_server::Handle;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tower::{Layer, Service};
+use crate::error::Error;
+
+/// Renders everything recorded with the `metrics` macros in the Prometheus text format
+async fn metrics_endpoint(Extension(handle): Extension<PrometheusHandle>) -> String {
+    handle.render()
+}
+
+/// Serves `/metrics` on its own address, so it is not reachable wherever the main server is
+pub(super) async fn serve_metrics(addr: SocketAddr, metrics_handle: PrometheusHandle, handle: Handle) -> Result<(), Error> {
+    let app = Router::new()
+        .route("/metrics", get(metrics_endpoint))
+        .layer(AddExtensionLayer::new(metrics_handle));
+    tracing::debug!("serving metrics on {}", addr);
+    axum_server::bind(addr)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Counts requests and their latency by route, method and status. Added with `Router::route_layer`, so
+/// that routes are labelled with their pattern (e.g. `/admin/users/:id`) and unmatched requests, which
+/// would have unbounded paths, are not recorded.
+#[derive(Clone)]
+pub struct RouteMetricsLayer;
+
+impl<S> Layer<S> for RouteMetricsLayer {
+    type Service = RouteMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RouteMetrics { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct RouteMetrics<S> {
+    inner: S
+}
+
+impl<S, B> Service<Request<B>> for RouteMetrics<S>
+    where
+        S: Service<Request<B>, Response=Response<BoxBody>, Error=Infallible> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        B: Send + 'static
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // The clone might not be ready, so the instance that was polled is used for this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let route = req.extensions().get::<MatchedPath>()
+            .map(|p| p.as_str().to_owned())
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let method = req.method().to_string();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let response = inner.call(req).await?;
+            let status = response.status().as_u16().to_string();
+
+            metrics::histogram!("tiauth_http_request_duration_seconds", start.elapsed().as_secs_f64(),
+                "route" => route.clone(), "method" => method.clone());
+            metrics::counter!("tiauth_http_requests_total", 1,
+                "route" => route, "method" => method, "status" => status);
+            Ok(response)
+        })
+    }
+}
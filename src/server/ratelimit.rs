@@ -0,0 +1,137 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum::body::BoxBody;
+use axum::http::{Request, Response};
+use axum::response::IntoResponse;
+use tower::{Layer, Service};
+use crate::config::RateLimitConfig;
+use crate::data::client::get_client;
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::error::Error;
+use crate::server::ip::client_ip;
+
+/// Token bucket rate limiting backed by the KV store, so that limits hold across replicas. Every
+/// request, except to exempt paths, takes a token from the bucket of its client address, and from the
+/// bucket of its route if that has a limit configured.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    dsrc: Arc<Source>,
+    config: Arc<RateLimitConfig>,
+    trust_forwarded_for: bool
+}
+
+impl RateLimitLayer {
+    pub fn new(dsrc: Arc<Source>, config: RateLimitConfig, trust_forwarded_for: bool) -> Self {
+        Self {
+            dsrc,
+            config: Arc::new(config),
+            trust_forwarded_for
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer
+}
+
+async fn retry_after<B>(layer: &RateLimitLayer, req: &Request<B>) -> Result<u64, Error> {
+    let path = req.uri().path();
+    if layer.config.exempt.iter().any(|exempt| exempt == path) {
+        return Ok(0)
+    }
+    let ip = match client_ip(req.headers(), req.extensions(), layer.trust_forwarded_for) {
+        Some(ip) => ip,
+        None => return Ok(0)
+    };
+    let client = &layer.config.client;
+    let mut retry_after = layer.dsrc.kv.take_token(&format!("rate_limit:client:{}", ip), client.capacity, client.refill_per_sec).await?;
+
+    if let Some(route) = layer.config.routes.iter().find(|r| r.path == path) {
+        let key = if route.global {
+            format!("rate_limit:route:{}", route.path)
+        } else {
+            format!("rate_limit:route:{}:{}", route.path, ip)
+        };
+        let route_retry = layer.dsrc.kv.take_token(&key, route.bucket.capacity, route.bucket.refill_per_sec).await?;
+        retry_after = retry_after.max(route_retry);
+    }
+
+    Ok(retry_after)
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+    where
+        S: Service<Request<B>, Response=Response<BoxBody>, Error=Infallible> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        B: Send + 'static
+{
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // The clone might not be ready, so the instance that was polled is used for this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            if !layer.config.enabled {
+                return inner.call(req).await
+            }
+            match retry_after(&layer, &req).await {
+                Ok(0) => inner.call(req).await,
+                Ok(retry_after) => Ok(Error::RateLimited(retry_after).into_response()),
+                Err(e) => {
+                    // Requests are let through, as refusing all of them would be worse
+                    tracing::error!("rate limiter unavailable: {}", e);
+                    inner.call(req).await
+                }
+            }
+        })
+    }
+}
+
+/// Takes a token from the bucket of an OAuth client and client address, if `oauth_client` is configured.
+/// Called by the handlers, as the client is only known once the request is parsed. Unknown clients are
+/// not charged, the handler rejects them, and the address keeps others from draining a client's bucket.
+pub(super) async fn check_oauth_client_limit(dsrc: &Source, config: &RateLimitConfig, client_id: &str, ip: &IpAddr) -> Result<(), Error> {
+    let bucket = match &config.oauth_client {
+        Some(bucket) if config.enabled => bucket,
+        _ => return Ok(())
+    };
+    if get_client(dsrc, client_id).await?.is_none() {
+        return Ok(())
+    }
+    let key = format!("rate_limit:oauth_client:{}:{}", client_id, ip);
+    match dsrc.kv.take_token(&key, bucket.capacity, bucket.refill_per_sec).await {
+        Ok(0) => Ok(()),
+        Ok(retry_after) => Err(Error::RateLimited(retry_after)),
+        Err(e) => {
+            tracing::error!("rate limiter unavailable: {}", e);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use axum::handler::Handler;
+use axum::http::Uri;
+use axum::response::Redirect;
+use axum::Router;
+use axum::routing::IntoMakeServiceWithConnectInfo;
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use crate::config::{ISS, TlsConfig};
+use crate::error::Error;
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the certificate and key when either file was modified, so renewed certificates are used
+/// without a restart. New connections use the new certificate, existing ones are unaffected.
+fn spawn_certificate_reload(rustls_config: RustlsConfig, config: TlsConfig) {
+    tokio::spawn(async move {
+        let mut last_modified = (modified(&config.cert_path), modified(&config.key_path));
+        let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval_secs));
+        loop {
+            interval.tick().await;
+            let current = (modified(&config.cert_path), modified(&config.key_path));
+            if current == last_modified {
+                continue
+            }
+            match rustls_config.reload_from_pem_file(&config.cert_path, &config.key_path).await {
+                Ok(()) => {
+                    tracing::info!("reloaded TLS certificate from {}", config.cert_path);
+                    last_modified = current;
+                }
+                // Keeps using the previous certificate, a renewal might have written only one of the files
+                Err(e) => tracing::warn!("failed to reload TLS certificate: {}", e)
+            }
+        }
+    });
+}
+
+/// Only the path and query are kept, the host of the request is not trusted
+async fn redirect_to_https(uri: Uri) -> Redirect {
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let target = format!("{}{}", ISS, path);
+    Redirect::permanent(target.parse().unwrap_or_else(|_| Uri::from_static(ISS)))
+}
+
+async fn serve_https_redirect(addr: SocketAddr, handle: Handle) -> Result<(), Error> {
+    let app = Router::new().fallback(redirect_to_https.into_service());
+    tracing::debug!("redirecting HTTP on {} to HTTPS", addr);
+    axum_server::bind(addr)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+/// Serves HTTPS until `handle` is shut down, along with the HTTP redirect if configured
+pub async fn serve_tls(app: IntoMakeServiceWithConnectInfo<Router, SocketAddr>, addr: SocketAddr, config: &TlsConfig, handle: Handle) -> Result<(), Error> {
+    let rustls_config = RustlsConfig::from_pem_file(&config.cert_path, &config.key_path).await
+        .map_err(|e| Error::TlsError(format!("failed to load {} and {}: {}", config.cert_path, config.key_path, e)))?;
+    spawn_certificate_reload(rustls_config.clone(), config.clone());
+
+    let https = axum_server::bind_rustls(addr, rustls_config)
+        .handle(handle.clone())
+        .serve(app);
+    tracing::debug!("listening with HTTPS on {}", addr);
+
+    match config.redirect_http_addr {
+        // If either server fails, for example because its address is taken, the other is stopped as well
+        Some(redirect_addr) => {
+            let https = async { Ok::<_, Error>(https.await?) };
+            tokio::try_join!(https, serve_https_redirect(redirect_addr, handle))?;
+            Ok(())
+        }
+        None => Ok(https.await?)
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use axum::extract::Extension;
+use axum::Json;
+use crate::auth::auth::{symmetric_crypt, symmetric_decrypt};
+use crate::auth::throttle::{reset_login_failures, start_login_attempt};
+use crate::auth::tokens::{AMR_OTP, get_symmetric_key_bytes};
+use crate::auth::totp::{encode_secret, hash_recovery_code, new_recovery_codes, new_secret, otpauth_uri, pending_totp_key, used_step_key, USED_STEP_EXP, verify_code};
+use crate::config::ISS;
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::data::totp::{get_totp_by_usph, save_recovery_codes, save_totp, use_recovery_code};
+use crate::error::Error;
+use crate::error::BadFlow::{ExpiredEnrollment, ExpiredMfaId};
+use crate::server::auth::{check_enrollment_allowed, complete_mfa, PENDING_MFA_EXP};
+use crate::server::bearer::AccountAuthenticated;
+use crate::server::ip::ClientIp;
+use crate::server::models::{ConfirmTotp, PendingMfa, PendingTotp, RecoveryCodes, TotpEnrollment, VerifyTotp};
+use crate::utility::{dec_b64url, enc_b64url, usp_dehex, utc_timestamp};
+
+const PENDING_TOTP_EXP: usize = 10 * 60;
+const MAX_MFA_ATTEMPTS: i64 = 5;
+
+fn mfa_attempts_key(mfa_id: &str) -> String {
+    format!("mfa_attempts:{}", mfa_id)
+}
+
+async fn encrypt_secret(dsrc: &Source, secret: &[u8]) -> Result<String, Error> {
+    let symmetric_key = get_symmetric_key_bytes(dsrc).await?;
+    Ok(enc_b64url(symmetric_crypt(&symmetric_key, secret.to_vec())?))
+}
+
+async fn decrypt_secret(dsrc: &Source, secret: &str) -> Result<Vec<u8>, Error> {
+    let symmetric_key = get_symmetric_key_bytes(dsrc).await?;
+    symmetric_decrypt(&symmetric_key, dec_b64url(secret)?)
+}
+
+fn issuer_name() -> String {
+    url::Url::parse(ISS).ok()
+        .and_then(|iss| iss.host_str().map(|h| h.to_owned()))
+        .unwrap_or_else(|| ISS.to_owned())
+}
+
+/// The secret only replaces an existing one once it is confirmed with a valid code
+pub async fn start_totp_enrollment(Extension(dsrc): Extension<Arc<Source>>, AccountAuthenticated(claims): AccountAuthenticated) -> Result<Json<TotpEnrollment>, Error> {
+    check_enrollment_allowed(&dsrc, &claims).await?;
+    let secret = new_secret();
+    let pending = PendingTotp {
+        secret: encrypt_secret(&dsrc, &secret).await?
+    };
+    dsrc.kv.store_json(&pending_totp_key(&claims.sub), &pending, PENDING_TOTP_EXP).await?;
+
+    Ok(Json(TotpEnrollment {
+        secret: encode_secret(&secret),
+        otpauth_uri: otpauth_uri(&secret, &issuer_name(), &usp_dehex(&claims.sub))
+    }))
+}
+
+/// Returns new recovery codes, which replace any existing ones
+pub async fn confirm_totp_enrollment(Json(confirm): Json<ConfirmTotp>, Extension(dsrc): Extension<Arc<Source>>, AccountAuthenticated(claims): AccountAuthenticated) -> Result<Json<RecoveryCodes>, Error> {
+    check_enrollment_allowed(&dsrc, &claims).await?;
+    let pending: PendingTotp = dsrc.kv.get_json(&pending_totp_key(&claims.sub)).await?
+        .ok_or(Error::BadFlow(ExpiredEnrollment))?;
+    let secret = decrypt_secret(&dsrc, &pending.secret).await?;
+    verify_code(&secret, &confirm.code, utc_timestamp()).ok_or(Error::IncorrectSecondFactor)?;
+
+    dsrc.kv.delete(&pending_totp_key(&claims.sub)).await?;
+    save_totp(&dsrc, &claims.sub, pending.secret).await?;
+
+    let recovery_codes = new_recovery_codes();
+    let code_hashes = recovery_codes.iter().map(|c| hash_recovery_code(c)).collect();
+    save_recovery_codes(&dsrc, &claims.sub, code_hashes).await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+async fn check_totp_code(dsrc: &Source, user_usph: &str, code: &str) -> Result<bool, Error> {
+    let totp = match get_totp_by_usph(dsrc, user_usph).await? {
+        Some(totp) => totp,
+        None => return Ok(false)
+    };
+    let secret = decrypt_secret(dsrc, &totp.secret).await?;
+    let step = match verify_code(&secret, code, utc_timestamp()) {
+        Some(step) => step,
+        None => return Ok(false)
+    };
+    // Each code can only be used once
+    Ok(dsrc.kv.incr(&used_step_key(user_usph, step), USED_STEP_EXP).await? == 1)
+}
+
+/// Second step of a login for users with TOTP, accepting either a code or a recovery code
+/// Failures count towards login throttling, like incorrect passwords
+pub async fn verify_login_totp(Json(verify): Json<VerifyTotp>, Extension(dsrc): Extension<Arc<Source>>, ClientIp(ip): ClientIp) -> Result<(), Error> {
+    let pending: PendingMfa = dsrc.kv.get_json(&verify.mfa_id).await?
+        .ok_or(Error::BadFlow(ExpiredMfaId))?;
+    if dsrc.kv.incr(&mfa_attempts_key(&verify.mfa_id), PENDING_MFA_EXP).await? > MAX_MFA_ATTEMPTS {
+        dsrc.kv.delete(&verify.mfa_id).await?;
+        return Err(Error::BadFlow(ExpiredMfaId))
+    }
+
+    let user_usph = &pending.flow_user.user_usph;
+    start_login_attempt(&dsrc, user_usph, &ip).await?;
+    let verified = if let Some(code) = &verify.code {
+        check_totp_code(&dsrc, user_usph, code).await?
+    } else if let Some(recovery_code) = &verify.recovery_code {
+        use_recovery_code(&dsrc, user_usph, &hash_recovery_code(recovery_code)).await?
+    } else {
+        false
+    };
+    if !verified {
+        return Err(Error::IncorrectSecondFactor)
+    }
+    reset_login_failures(&dsrc, user_usph, &ip).await?;
+
+    complete_mfa(&dsrc, &verify.mfa_id, pending, AMR_OTP).await
+}
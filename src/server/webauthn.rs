@@ -0,0 +1,148 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use axum::extract::Extension;
+use axum::Json;
+use webauthn_rs::prelude::{CreationChallengeResponse, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Webauthn};
+use crate::auth::tokens::{AMR_HWK, AMR_MFA};
+use crate::auth::webauthn::{finish_authentication, finish_registration, registration_key, start_authentication, start_registration};
+use crate::data::audit::{AuditKind, NewAuditEvent, record};
+use crate::data::kv::KeyValue;
+use crate::data::source::Source;
+use crate::data::user;
+use crate::data::webauthn::{get_passkeys_by_usph, save_passkey, update_passkey};
+use crate::error::Error;
+use crate::error::BadFlow::{ExpiredAuthId, ExpiredEnrollment, ExpiredMfaId};
+use crate::server::auth::{check_enrollment_allowed, complete_mfa, PENDING_MFA_EXP};
+use crate::server::bearer::AccountAuthenticated;
+use crate::server::ip::ClientIp;
+use crate::server::models::{FinishWebauthnLogin, FinishWebauthnMfa, FlowUser, PendingMfa, StartWebauthnLogin, StartWebauthnMfa, WebauthnLoginChallenge, WebauthnLoginResponse, WebauthnLoginState};
+use crate::utility::{random_time_hash_hex, rng_urlsafe, usp_hex, utc_timestamp};
+
+const WEBAUTHN_STATE_EXP: usize = 5 * 60;
+
+fn mfa_challenge_key(mfa_id: &str) -> String {
+    format!("webauthn_mfa:{}", mfa_id)
+}
+
+fn login_event(kind: AuditKind, user_usph: &str, ip: &IpAddr) -> NewAuditEvent {
+    NewAuditEvent::new(kind)
+        .user(user_usph)
+        .ip(ip)
+        .detail("method", "webauthn")
+}
+
+/// Returns whether the user was verified by the authenticator
+async fn verify_assertion(dsrc: &Source, webauthn: &Webauthn, user_usph: &str, credential: &PublicKeyCredential, state: &PasskeyAuthentication) -> Result<bool, Error> {
+    let passkeys = get_passkeys_by_usph(dsrc, user_usph).await?;
+    let (passkey, user_verified) = finish_authentication(webauthn, credential, state, passkeys)?;
+    update_passkey(dsrc, user_usph, &passkey).await?;
+    Ok(user_verified)
+}
+
+/// A passkey allows logging in without a password, so it can only be added after a recent login
+pub async fn start_webauthn_registration(Extension(dsrc): Extension<Arc<Source>>, Extension(webauthn): Extension<Arc<Webauthn>>, AccountAuthenticated(claims): AccountAuthenticated) -> Result<Json<CreationChallengeResponse>, Error> {
+    check_enrollment_allowed(&dsrc, &claims).await?;
+    let existing = get_passkeys_by_usph(&dsrc, &claims.sub).await?;
+    let (challenge, state) = start_registration(&webauthn, &claims.sub, &existing)?;
+    dsrc.kv.store_json(&registration_key(&claims.sub), &state, WEBAUTHN_STATE_EXP).await?;
+
+    Ok(Json(challenge))
+}
+
+pub async fn finish_webauthn_registration(Json(credential): Json<RegisterPublicKeyCredential>, Extension(dsrc): Extension<Arc<Source>>, Extension(webauthn): Extension<Arc<Webauthn>>, AccountAuthenticated(claims): AccountAuthenticated) -> Result<(), Error> {
+    check_enrollment_allowed(&dsrc, &claims).await?;
+    let state: PasskeyRegistration = dsrc.kv.get_json(&registration_key(&claims.sub)).await?
+        .ok_or(Error::BadFlow(ExpiredEnrollment))?;
+    dsrc.kv.delete(&registration_key(&claims.sub)).await?;
+
+    let passkey = finish_registration(&webauthn, &credential, &state)?;
+    save_passkey(&dsrc, &claims.sub, &passkey).await
+}
+
+/// Passwordless login. Users that do not exist or are disabled get the same error as users without
+/// passkeys.
+pub async fn start_webauthn_login(Json(login_start): Json<StartWebauthnLogin>, Extension(dsrc): Extension<Arc<Source>>, Extension(webauthn): Extension<Arc<Webauthn>>) -> Result<Json<WebauthnLoginChallenge>, Error> {
+    let user_usph = usp_hex(&login_start.username);
+    let disabled = user::get_user_by_usph(&dsrc, &user_usph).await?
+        .map(|u| u.disabled)
+        .unwrap_or(true);
+    let passkeys = get_passkeys_by_usph(&dsrc, &user_usph).await?;
+    if disabled || passkeys.is_empty() {
+        return Err(Error::NoWebauthnCredentials)
+    }
+
+    let (challenge, state) = start_authentication(&webauthn, &passkeys)?;
+    let auth_id = random_time_hash_hex(Some(user_usph.as_bytes()));
+    let login_state = WebauthnLoginState {
+        user_usph,
+        flow_id: login_start.flow_id,
+        state
+    };
+    dsrc.kv.store_json(&auth_id, &login_state, WEBAUTHN_STATE_EXP).await?;
+
+    Ok(Json(WebauthnLoginChallenge {
+        auth_id,
+        challenge
+    }))
+}
+
+/// Stores the same `FlowUser` as an OPAQUE login, under a newly generated code
+pub async fn finish_webauthn_login(Json(login_finish): Json<FinishWebauthnLogin>, Extension(dsrc): Extension<Arc<Source>>, Extension(webauthn): Extension<Arc<Webauthn>>, ClientIp(ip): ClientIp) -> Result<Json<WebauthnLoginResponse>, Error> {
+    let login_state: WebauthnLoginState = dsrc.kv.get_json(&login_finish.auth_id).await?
+        .ok_or(Error::BadFlow(ExpiredAuthId))?;
+    if !dsrc.kv.delete(&login_finish.auth_id).await? {
+        return Err(Error::BadFlow(ExpiredAuthId))
+    }
+
+    let user_verified = match verify_assertion(&dsrc, &webauthn, &login_state.user_usph, &login_finish.credential, &login_state.state).await {
+        Ok(user_verified) => user_verified,
+        Err(e) => {
+            record(&dsrc, login_event(AuditKind::LoginFailed, &login_state.user_usph, &ip)).await;
+            return Err(e)
+        }
+    };
+    record(&dsrc, login_event(AuditKind::Login, &login_state.user_usph, &ip).detail("user_verified", user_verified)).await;
+    let mut amr = vec![AMR_HWK.to_owned()];
+    // Verification by PIN or biometrics makes the passkey a second factor by itself
+    if user_verified {
+        amr.push(AMR_MFA.to_owned());
+    }
+
+    let flow_user = FlowUser {
+        user_usph: login_state.user_usph,
+        flow_id: login_state.flow_id,
+        auth_time: utc_timestamp(),
+        amr
+    };
+    let code = rng_urlsafe(32);
+    dsrc.kv.store_json(&code, &flow_user, 60).await?;
+
+    Ok(Json(WebauthnLoginResponse { code }))
+}
+
+pub async fn start_webauthn_mfa(Json(mfa_start): Json<StartWebauthnMfa>, Extension(dsrc): Extension<Arc<Source>>, Extension(webauthn): Extension<Arc<Webauthn>>) -> Result<Json<RequestChallengeResponse>, Error> {
+    let pending: PendingMfa = dsrc.kv.get_json(&mfa_start.mfa_id).await?
+        .ok_or(Error::BadFlow(ExpiredMfaId))?;
+    let passkeys = get_passkeys_by_usph(&dsrc, &pending.flow_user.user_usph).await?;
+    if passkeys.is_empty() {
+        return Err(Error::NoWebauthnCredentials)
+    }
+
+    let (challenge, state) = start_authentication(&webauthn, &passkeys)?;
+    dsrc.kv.store_json(&mfa_challenge_key(&mfa_start.mfa_id), &state, PENDING_MFA_EXP).await?;
+
+    Ok(Json(challenge))
+}
+
+pub async fn finish_webauthn_mfa(Json(mfa_finish): Json<FinishWebauthnMfa>, Extension(dsrc): Extension<Arc<Source>>, Extension(webauthn): Extension<Arc<Webauthn>>) -> Result<(), Error> {
+    let pending: PendingMfa = dsrc.kv.get_json(&mfa_finish.mfa_id).await?
+        .ok_or(Error::BadFlow(ExpiredMfaId))?;
+    let state: PasskeyAuthentication = dsrc.kv.get_json(&mfa_challenge_key(&mfa_finish.mfa_id)).await?
+        .ok_or(Error::BadFlow(ExpiredMfaId))?;
+    // Every challenge can only be answered once
+    dsrc.kv.delete(&mfa_challenge_key(&mfa_finish.mfa_id)).await?;
+
+    verify_assertion(&dsrc, &webauthn, &pending.flow_user.user_usph, &mfa_finish.credential, &state).await?;
+
+    complete_mfa(&dsrc, &mfa_finish.mfa_id, pending, AMR_HWK).await
+}
@@ -51,6 +51,21 @@ pub fn usp_hex(utf_str: &str) -> String {
     anp_base6url_str
 }
 
+/// Inverse of `usp_hex`, for displaying a username
+pub fn usp_dehex(usp_hex: &str) -> String {
+    let mut utf_bytes = Vec::with_capacity(usp_hex.len());
+    let mut chars = usp_hex.bytes();
+    while let Some(bt) = chars.next() {
+        if bt == b'~' {
+            let hex_pair = [chars.next().unwrap_or(b'0'), chars.next().unwrap_or(b'0')];
+            utf_bytes.extend(hex::decode(hex_pair).unwrap_or_default());
+        } else {
+            utf_bytes.push(bt)
+        }
+    }
+    String::from_utf8_lossy(&utf_bytes).into_owned()
+}
+
 pub fn enc_b64url<T: AsRef<[u8]>>(to_enc: T) -> String {
     base64::encode_config(to_enc, base64::URL_SAFE_NO_PAD)
 }
@@ -89,5 +104,7 @@ mod tests {
     fn test_usp_hex() {
         assert_eq!(usp_hex("ka25kja5kasdf;lkja@@@!!!😂s"),
         "ka25kja5kasdf~3blkja~40~40~40~21~21~21~f0~9f~98~82s".to_string());
+        assert_eq!(usp_dehex("ka25kja5kasdf~3blkja~40~40~40~21~21~21~f0~9f~98~82s"),
+        "ka25kja5kasdf;lkja@@@!!!😂s".to_string());
     }
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, Ident, Meta, NestedMeta};
+
+/// Implements `data::db::Row` for references to a struct with named fields. Every field is a column
+/// with the same name. The id column is the field named `id`, or the field marked `#[row(id)]`, and
+/// fields marked `#[row(skip)]` are left out. Field values must be convertible into a
+/// `sea_query::Value`.
+#[proc_macro_derive(Row, attributes(row))]
+pub fn derive_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_row(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[derive(PartialEq)]
+enum Marker {
+    None,
+    Id,
+    Skip,
+}
+
+fn field_marker(field: &Field) -> syn::Result<Marker> {
+    let mut marker = Marker::None;
+    for attr in field.attrs.iter().filter(|a| a.path.is_ident("row")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            other => return Err(syn::Error::new_spanned(other, "expected #[row(id)] or #[row(skip)]")),
+        };
+        for nested in list.nested {
+            marker = match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("id") => Marker::Id,
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => Marker::Skip,
+                other => return Err(syn::Error::new_spanned(other, "expected `id` or `skip`")),
+            };
+        }
+    }
+    Ok(marker)
+}
+
+fn column_name(ident: &Ident) -> String {
+    let name = ident.to_string();
+    name.strip_prefix("r#").map(str::to_owned).unwrap_or(name)
+}
+
+fn expand_row(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(input, "Row requires named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(input, "Row can only be derived for structs")),
+    };
+
+    let mut marked = Vec::with_capacity(fields.len());
+    for field in fields {
+        marked.push((field.ident.clone().unwrap(), field_marker(field)?));
+    }
+
+    let explicit_ids: Vec<&Ident> = marked.iter()
+        .filter(|(_, marker)| *marker == Marker::Id)
+        .map(|(ident, _)| ident)
+        .collect();
+    let id = match explicit_ids.as_slice() {
+        [id] => (*id).clone(),
+        [] => marked.iter()
+            .find(|(ident, marker)| *marker == Marker::None && ident == "id")
+            .map(|(ident, _)| ident.clone())
+            .ok_or_else(|| syn::Error::new_spanned(input, "Row requires a field `id` or a field marked #[row(id)]"))?,
+        _ => return Err(syn::Error::new_spanned(input, "only one field can be marked #[row(id)]")),
+    };
+
+    let columns: Vec<&Ident> = marked.iter()
+        .filter(|(ident, marker)| *marker == Marker::None && *ident != id)
+        .map(|(ident, _)| ident)
+        .collect();
+
+    let id_name = column_name(&id);
+    let column_names: Vec<String> = columns.iter().map(|ident| column_name(ident)).collect();
+
+    let name = &input.ident;
+
+    Ok(quote! {
+        impl<'a> crate::data::db::Row for &'a #name {
+            fn id_column(&self) -> &'static str {
+                #id_name
+            }
+
+            fn columns(&self, include_id: bool) -> ::std::vec::Vec<&'static str> {
+                let mut columns = vec![ #( #column_names, )* ];
+                if include_id {
+                    columns.insert(0, #id_name);
+                }
+                columns
+            }
+
+            fn values(&self, include_id: bool) -> ::sea_query::Values {
+                let mut val_vec = vec![
+                    #( ::sea_query::Value::from(::core::clone::Clone::clone(&self.#columns)), )*
+                ];
+
+                if include_id {
+                    val_vec.insert(0, ::sea_query::Value::from(::core::clone::Clone::clone(&self.#id)));
+                }
+
+                ::sea_query::Values(val_vec)
+            }
+        }
+    })
+}
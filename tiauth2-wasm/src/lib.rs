@@ -0,0 +1,47 @@
+//! The client side of OPAQUE for the bundled UI. Messages and states are passed as the same encoded
+//! strings the server endpoints use, so the UI only moves them between these functions and `fetch`.
+
+use opaquebind::client::{login_client, login_client_finish, register_client, register_client_finish};
+use wasm_bindgen::prelude::*;
+
+fn js_error(e: opaquebind::Error) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+/// A request and state pair, as wasm-bindgen cannot return tuples
+#[wasm_bindgen(getter_with_clone)]
+pub struct ClientStart {
+    pub message: String,
+    pub state: String
+}
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct LoginFinish {
+    pub message: String,
+    pub session_key: String
+}
+
+#[wasm_bindgen(js_name = registerStart)]
+pub fn register_start(password: String) -> Result<ClientStart, JsValue> {
+    let (message, state) = register_client(password).map_err(js_error)?;
+    Ok(ClientStart { message, state })
+}
+
+#[wasm_bindgen(js_name = registerFinish)]
+pub fn register_finish(state: String, server_message: String) -> Result<String, JsValue> {
+    register_client_finish(state, server_message).map_err(js_error)
+}
+
+#[wasm_bindgen(js_name = loginStart)]
+pub fn login_start(password: String) -> Result<ClientStart, JsValue> {
+    let (message, state) = login_client(password).map_err(js_error)?;
+    Ok(ClientStart { message, state })
+}
+
+/// The session key is only known to the client if the password was correct, and is used as the code
+/// for the rest of the OAuth flow
+#[wasm_bindgen(js_name = loginFinish)]
+pub fn login_finish(state: String, server_message: String) -> Result<LoginFinish, JsValue> {
+    let (message, session_key) = login_client_finish(state, server_message).map_err(js_error)?;
+    Ok(LoginFinish { message, session_key })
+}
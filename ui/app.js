@@ -0,0 +1,117 @@
+// Shared by the login, registration and password reset pages. The OPAQUE client is compiled to WASM from tiauth2-wasm.
+import init, { loginFinish, loginStart, registerFinish, registerStart } from "./pkg/tiauth2_wasm.js";
+
+const wasm = init();
+
+export function param(name) {
+    return new URLSearchParams(window.location.search).get(name);
+}
+
+export function showError(element, message) {
+    element.textContent = message;
+    element.hidden = false;
+}
+
+async function post(path, body, unauthorized = "Incorrect username or password.") {
+    const response = await fetch(path, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(body)
+    });
+    if (response.status === 429) {
+        throw new Error(`Too many attempts, try again in ${response.headers.get("Retry-After")} seconds.`);
+    }
+    if (!response.ok) {
+        throw new Error(response.status === 401 ? unauthorized : "Something went wrong, please try again.");
+    }
+    const text = await response.text();
+    return text ? JSON.parse(text) : null;
+}
+
+// Returns the session key, which is the code for the rest of the flow, and the MFA methods if a second
+// factor is required
+export async function login(username, password, flowId) {
+    await wasm;
+    const start = loginStart(password);
+    const started = await post("/login/start/", { username, client_request: start.message });
+    let finish;
+    try {
+        finish = loginFinish(start.state, started.server_message);
+    } catch (e) {
+        throw new Error("Incorrect username or password.");
+    }
+    const finished = await post("/login/finish/", {
+        auth_id: started.auth_id,
+        username,
+        client_request: finish.message,
+        flow_id: flowId
+    });
+    return { code: finish.session_key, mfa: finished.mfa_required ? finished : null };
+}
+
+export async function verifyTotp(mfaId, code) {
+    // Recovery codes are longer than the six digit TOTP codes
+    const body = code.length > 6 ? { mfa_id: mfaId, recovery_code: code } : { mfa_id: mfaId, code };
+    await post("/login/mfa/totp/", body, "Incorrect code.");
+}
+
+function fromB64url(value) {
+    const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+    return Uint8Array.from(atob(base64), c => c.charCodeAt(0));
+}
+
+function toB64url(buffer) {
+    const bytes = String.fromCharCode(...new Uint8Array(buffer));
+    return btoa(bytes).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+export async function verifyWebauthn(mfaId) {
+    const challenge = await post("/login/mfa/webauthn/start/", { mfa_id: mfaId });
+    const publicKey = challenge.publicKey;
+    publicKey.challenge = fromB64url(publicKey.challenge);
+    publicKey.allowCredentials = (publicKey.allowCredentials || []).map(c => ({ ...c, id: fromB64url(c.id) }));
+
+    const credential = await navigator.credentials.get({ publicKey });
+    await post("/login/mfa/webauthn/finish/", {
+        mfa_id: mfaId,
+        credential: {
+            id: credential.id,
+            rawId: toB64url(credential.rawId),
+            type: credential.type,
+            extensions: {},
+            response: {
+                authenticatorData: toB64url(credential.response.authenticatorData),
+                clientDataJSON: toB64url(credential.response.clientDataJSON),
+                signature: toB64url(credential.response.signature),
+                userHandle: credential.response.userHandle ? toB64url(credential.response.userHandle) : null
+            }
+        }
+    }, "The security key was not accepted.");
+}
+
+export async function register(username, password, email) {
+    await wasm;
+    const start = registerStart(password);
+    const started = await post("/register/start/", { username, client_request: start.message });
+    const message = registerFinish(start.state, started.server_message);
+    await post("/register/finish/", {
+        auth_id: started.auth_id,
+        username,
+        client_request: message,
+        email: email || null
+    });
+}
+
+// The token is the one from the link in the recovery mail
+export async function resetPassword(token, password) {
+    await wasm;
+    const start = registerStart(password);
+    const started = await post("/recovery/reset/start/", { token, client_request: start.message });
+    const message = registerFinish(start.state, started.server_message);
+    await post("/recovery/reset/finish/", { auth_id: started.auth_id, client_request: message });
+}
+
+export function continueFlow(flowId, code) {
+    const query = new URLSearchParams({ flow_id: flowId, code });
+    window.location.assign(`/oauth/consent/?${query}`);
+}